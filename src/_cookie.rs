@@ -1,65 +1,91 @@
 use chrono::{Duration, NaiveDateTime};
-use data_encoding::BASE64;
+use data_encoding::{BASE64, BASE64URL_NOPAD};
 use percent_encoding::{percent_decode, utf8_percent_encode, AsciiSet, CONTROLS};
-use ring::hmac::{sign, Key};
-use serde::de::{Deserializer, Error};
-use serde::ser::Serializer;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use uuid::Uuid;
 
+use crate::{Keyring, SessionError, SigningKey};
+
 const PERCENT_ENCODING_ASCII_SET: &AsciiSet = &CONTROLS.add(b':').add(b'=');
 
+/// version prefix of signed cookies, `s:<id>.<signature>`
+const SIGNED_COOKIE_PREFIX: &str = "s:";
+/// version prefix of encrypted cookies, `e1:<nonce, ciphertext and tag>`
+const ENCRYPTED_COOKIE_PREFIX: &str = "e1:";
+
+/// largest payload which can be carried by an encrypted cookie, keeping the cookie well within browser limits
+pub const MAX_COOKIE_PAYLOAD_LEN: usize = 1024;
+
 #[derive(Clone, Debug)]
 pub struct CookieValue {
     pub id: Uuid,
-    pub signature: String,
+    /// small value carried alongside the session id, only supported by encrypted cookies
+    pub payload: Option<Vec<u8>>,
 }
 
 impl CookieValue {
-    pub(crate) fn new(key: &Key) -> Result<Self, anyhow::Error> {
-        let id = Uuid::new_v4();
-        let signature = BASE64.encode(sign(key, id.as_bytes()).as_ref());
-        Ok(Self { id, signature })
+    pub(crate) fn new(payload: Option<Vec<u8>>) -> Result<Self, SessionError> {
+        if matches!(payload.as_ref(), Some(payload) if payload.len() > MAX_COOKIE_PAYLOAD_LEN) {
+            return Err(SessionError::cookie(format!(
+                "cookie payloads may not be longer than {MAX_COOKIE_PAYLOAD_LEN} bytes"
+            )));
+        }
+        Ok(Self {
+            id: Uuid::new_v4(),
+            payload,
+        })
     }
 
-    pub(crate) fn encode(&self) -> String {
-        serde_plain::to_string(&self).unwrap()
+    /// signs or encrypts the cookie value with the keyring's primary key, depending on whether the keyring
+    /// encrypts cookies, and formats it for use in a cookie
+    pub(crate) fn encode(&self, keyring: &Keyring) -> Result<String, SessionError> {
+        let formatted = if keyring.encrypts_cookies() {
+            let mut plaintext = self.id.as_bytes().to_vec();
+            plaintext.extend(self.payload.iter().flatten());
+            let sealed = keyring
+                .encrypt(ENCRYPTED_COOKIE_PREFIX.as_bytes(), &plaintext)
+                .map_err(|_| SessionError::cookie("unable to encrypt cookie value"))?;
+            format!("{ENCRYPTED_COOKIE_PREFIX}{}", BASE64URL_NOPAD.encode(&sealed))
+        } else {
+            if self.payload.is_some() {
+                return Err(SessionError::cookie("cookie payloads require encrypted cookies"));
+            }
+            format!(
+                "{SIGNED_COOKIE_PREFIX}{}.{}",
+                self.id.as_simple().encode_lower(&mut Uuid::encode_buffer()),
+                BASE64.encode(keyring.sign(self.id.as_bytes()).as_ref()),
+            )
+        };
+        Ok(utf8_percent_encode(&formatted, PERCENT_ENCODING_ASCII_SET).to_string())
     }
-}
 
-impl Serialize for CookieValue {
-    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let formatted = format!(
-            "s:{}.{}",
-            self.id.as_simple().encode_lower(&mut Uuid::encode_buffer()),
-            self.signature
-        );
-        let encoded = utf8_percent_encode(&formatted, PERCENT_ENCODING_ASCII_SET).to_string();
-        serializer.collect_str(&encoded)
-    }
-}
+    /// parses a signed or encrypted cookie value, returning it along with the key which signed or encrypted it
+    /// if it was produced by one of the keyring's accepted keys
+    pub(crate) fn decode<'a>(encoded: &str, keyring: &'a Keyring) -> Option<(Self, &'a SigningKey)> {
+        let cookie_value = percent_decode(encoded.as_bytes()).decode_utf8().ok()?;
 
-impl<'de> Deserialize<'de> for CookieValue {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let encoded_cookie_value = String::deserialize(deserializer)?;
-        let cookie_value = percent_decode(encoded_cookie_value.as_bytes())
-            .decode_utf8()
-            .map_err(|_| D::Error::custom("invalid cookie value"))?;
-        if &cookie_value[0..2] != "s:" {
-            return Err(D::Error::custom("invalid cookie value"));
+        if let Some(signed) = cookie_value.strip_prefix(SIGNED_COOKIE_PREFIX) {
+            let (id, signature) = signed.split_once('.')?;
+            let id = Uuid::parse_str(id).ok()?;
+            let signature = BASE64.decode(signature.as_bytes()).ok()?;
+            let key = keyring.verify(id.as_bytes(), &signature)?;
+            return Some((Self { id, payload: None }, key));
         }
-        let mut sequence: Vec<_> = cookie_value[2..].split('.').map(|item| item.to_owned()).collect();
-        if sequence.len() != 2 {
-            return Err(D::Error::custom("invalid cookie value"));
+
+        if let Some(encrypted) = cookie_value.strip_prefix(ENCRYPTED_COOKIE_PREFIX) {
+            let sealed = BASE64URL_NOPAD.decode(encrypted.as_bytes()).ok()?;
+            let (plaintext, key) = keyring.decrypt(ENCRYPTED_COOKIE_PREFIX.as_bytes(), &sealed)?;
+            if plaintext.len() < 16 {
+                return None;
+            }
+            let (id, payload) = plaintext.split_at(16);
+            let id = Uuid::from_slice(id).ok()?;
+            let payload = (!payload.is_empty()).then(|| payload.to_vec());
+            return Some((Self { id, payload }, key));
         }
-        let signature = sequence.pop().unwrap();
-        let id = Uuid::parse_str(&sequence.pop().unwrap()).map_err(|_| D::Error::custom("invalid cookie value"))?;
 
-        Ok(CookieValue { signature, id })
+        None
     }
 }
 
@@ -73,6 +99,12 @@ pub struct CookieConfig<'a, T: 'a + Clone> {
     pub path: Option<Cow<'a, str>>,
     pub max_age: Option<Duration>,
     pub expires: Option<NaiveDateTime>,
+    /// how long the stored session may go unused before it expires
+    pub idle_timeout: Option<Duration>,
+    /// how long the stored session lasts regardless of how active it is
+    pub max_lifetime: Option<Duration>,
+    /// small value kept confidential in the cookie itself, requires a keyring which encrypts cookies
+    pub payload: Option<Cow<'a, [u8]>>,
 }
 
 impl<'a, T: 'a + Clone + Deserialize<'a> + Serialize> CookieConfig<'a, T> {
@@ -85,6 +117,9 @@ impl<'a, T: 'a + Clone + Deserialize<'a> + Serialize> CookieConfig<'a, T> {
             path: None,
             max_age: None,
             expires: None,
+            idle_timeout: None,
+            max_lifetime: None,
+            payload: None,
             value,
         }
     }
@@ -108,6 +143,18 @@ impl<'a, T: 'a + Clone + Deserialize<'a> + Serialize> CookieConfig<'a, T> {
         self.expires = expires.into();
         self
     }
+    pub fn idle_timeout(mut self, idle_timeout: impl Into<Option<Duration>>) -> Self {
+        self.idle_timeout = idle_timeout.into();
+        self
+    }
+    pub fn max_lifetime(mut self, max_lifetime: impl Into<Option<Duration>>) -> Self {
+        self.max_lifetime = max_lifetime.into();
+        self
+    }
+    pub fn payload<S: Into<Cow<'a, [u8]>>>(mut self, payload: impl Into<Option<S>>) -> Self {
+        self.payload = payload.into().map(Into::into);
+        self
+    }
     pub fn domain<S: Into<Cow<'a, str>>>(mut self, domain: impl Into<Option<S>>) -> Self {
         self.domain = domain.into().map(Into::into);
         self
@@ -134,3 +181,113 @@ impl std::fmt::Display for SameSite {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    /// a cookie value in the format issued before keyrings and encrypted cookies, signed with the secret `secret`
+    const LEGACY_COOKIE_VALUE: &str =
+        "s%3A6f1c1b6a3d3e4c6b9b3e1f2a3b4c5d6e.nkz2acv0/SeMORTVjwZJFk2ejemW+H6x0QeUf/M8+qM%3D";
+
+    fn encrypting(keyring: Keyring) -> Keyring {
+        keyring.encrypt_cookies(true)
+    }
+
+    #[test]
+    fn legacy_signed_cookies_verify() {
+        let keyring = Keyring::from("secret");
+        let (cookie_value, key) = CookieValue::decode(LEGACY_COOKIE_VALUE, &keyring).unwrap();
+        assert_eq!(
+            cookie_value.id,
+            Uuid::parse_str("6f1c1b6a-3d3e-4c6b-9b3e-1f2a3b4c5d6e").unwrap()
+        );
+        assert!(cookie_value.payload.is_none());
+        assert_eq!(key.id(), crate::DEFAULT_KEY_ID);
+
+        // encrypting keyrings still accept signed cookies
+        assert!(CookieValue::decode(LEGACY_COOKIE_VALUE, &encrypting(keyring)).is_some());
+        assert!(CookieValue::decode(LEGACY_COOKIE_VALUE, &Keyring::from("other secret")).is_none());
+    }
+
+    #[test]
+    fn signed_cookies_round_trip() {
+        let keyring = Keyring::from("secret");
+        let cookie_value = CookieValue::new(None).unwrap();
+        let encoded = cookie_value.encode(&keyring).unwrap();
+        assert!(encoded.starts_with("s%3A"));
+
+        let (decoded, _) = CookieValue::decode(&encoded, &keyring).unwrap();
+        assert_eq!(decoded.id, cookie_value.id);
+        assert!(decoded.payload.is_none());
+    }
+
+    #[test]
+    fn signed_cookies_reject_payloads() {
+        let cookie_value = CookieValue::new(Some(b"payload".to_vec())).unwrap();
+        assert!(matches!(
+            cookie_value.encode(&Keyring::from("secret")),
+            Err(SessionError::Cookie(_))
+        ));
+    }
+
+    #[test]
+    fn tampered_signed_cookies_are_rejected() {
+        let keyring = Keyring::from("secret");
+        let tampered = LEGACY_COOKIE_VALUE.replacen("6f1c", "7f1c", 1);
+        assert!(CookieValue::decode(&tampered, &keyring).is_none());
+    }
+
+    #[test]
+    fn encrypted_cookies_round_trip() {
+        let keyring = encrypting(Keyring::from("secret"));
+        for payload in [None, Some(b"payload".to_vec())] {
+            let cookie_value = CookieValue::new(payload.clone()).unwrap();
+            let encoded = cookie_value.encode(&keyring).unwrap();
+            assert!(encoded.starts_with("e1%3A"));
+            assert!(!encoded.contains(&cookie_value.id.as_simple().to_string()));
+
+            let (decoded, key) = CookieValue::decode(&encoded, &keyring).unwrap();
+            assert_eq!(decoded.id, cookie_value.id);
+            assert_eq!(decoded.payload, payload);
+            assert_eq!(key.id(), crate::DEFAULT_KEY_ID);
+            assert!(CookieValue::decode(&encoded, &encrypting(Keyring::from("other secret"))).is_none());
+        }
+    }
+
+    #[test]
+    fn payloads_are_limited_in_length() {
+        assert!(CookieValue::new(Some(vec![0; MAX_COOKIE_PAYLOAD_LEN])).is_ok());
+        assert!(matches!(
+            CookieValue::new(Some(vec![0; MAX_COOKIE_PAYLOAD_LEN + 1])),
+            Err(SessionError::Cookie(_))
+        ));
+    }
+
+    #[test]
+    fn retired_keys_are_accepted_until_their_grace_period_ends() {
+        let old = Keyring::new(SigningKey::new("old", "old secret"));
+        let cookie_value = CookieValue::new(None).unwrap();
+        let signed = cookie_value.encode(&old).unwrap();
+        let encrypted = cookie_value.encode(&encrypting(old)).unwrap();
+
+        let accepting = Keyring::new(SigningKey::new("new", "new secret")).retire(
+            SigningKey::new("old", "old secret"),
+            Utc::now().naive_utc() + Duration::hours(1),
+        );
+        for encoded in [&signed, &encrypted] {
+            let (decoded, key) = CookieValue::decode(encoded, &accepting).unwrap();
+            assert_eq!(decoded.id, cookie_value.id);
+            assert_eq!(key.id(), "old");
+        }
+
+        let expired = Keyring::new(SigningKey::new("new", "new secret")).retire(
+            SigningKey::new("old", "old secret"),
+            Utc::now().naive_utc() - Duration::seconds(1),
+        );
+        for encoded in [&signed, &encrypted] {
+            assert!(CookieValue::decode(encoded, &expired).is_none());
+        }
+    }
+}
@@ -0,0 +1,419 @@
+use crate::*;
+use ::chrono::Duration;
+use ::data_encoding::BASE64;
+use ::http::HeaderMap;
+use ::serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use ::serde::{Deserialize, Serialize, Serializer};
+use ::std::fmt;
+use ::std::marker::PhantomData;
+use ::uuid::Uuid;
+
+/// session value encoded and compressed by a `CompressedStore`, which the wrapped store keeps in place of the value
+///
+/// serialized as a base64 string by human readable formats such as json and as bytes by binary formats
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompressedValue(pub Vec<u8>);
+
+impl Serialize for CompressedValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match serializer.is_human_readable() {
+            true => serializer.serialize_str(&BASE64.encode(&self.0)),
+            false => serializer.serialize_bytes(&self.0),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CompressedValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct CompressedValueVisitor;
+
+        impl<'de> Visitor<'de> for CompressedValueVisitor {
+            type Value = CompressedValue;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a base64 string or bytes")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                BASE64.decode(v.as_bytes()).map(CompressedValue).map_err(E::custom)
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                Ok(CompressedValue(v.to_vec()))
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(CompressedValue(v))
+            }
+
+            fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(CompressedValue(bytes))
+            }
+        }
+
+        match deserializer.is_human_readable() {
+            true => deserializer.deserialize_str(CompressedValueVisitor),
+            false => deserializer.deserialize_byte_buf(CompressedValueVisitor),
+        }
+    }
+}
+
+/// codecs identify json by its opening brace, so values are encoded within an object whatever their type
+#[derive(Deserialize, Serialize)]
+struct EncodedValue<T> {
+    value: T,
+}
+
+/// session store which encodes and compresses session values before passing them to any other store, e.g. a
+/// `PostgresStore<CompressedValue>` or `SqliteStore<CompressedValue>`, for stores which have no compression of
+/// their own
+///
+/// only the session's value is compressed, the rest of the session is kept by the wrapped store as is, values
+/// smaller than the compression's threshold are encoded but left uncompressed
+#[derive(Clone)]
+pub struct CompressedStore<S, T> {
+    store: S,
+    codec: Codec,
+    compression: Compression,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<S: fmt::Debug, T> fmt::Debug for CompressedStore<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompressedStore")
+            .field("store", &self.store)
+            .field("codec", &self.codec)
+            .field("compression", &self.compression)
+            .finish()
+    }
+}
+
+impl<S, T> CompressedStore<S, T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    /// the wrapped store
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    fn encode(&self, value: &T) -> Result<CompressedValue, SessionError> {
+        self.codec
+            .encode_compressed(&EncodedValue { value }, Some(&self.compression))
+            .map(CompressedValue)
+    }
+
+    fn decode(&self, value: &CompressedValue) -> Result<T, SessionError> {
+        Codec::decode::<EncodedValue<T>>(&value.0).map(|encoded| encoded.value)
+    }
+
+    fn decode_session(&self, session: Session<CompressedValue>) -> Result<Session<T>, SessionError> {
+        session.try_map(|value| self.decode(&value))
+    }
+}
+
+#[async_trait]
+impl<S, T> SessionStore for CompressedStore<S, T>
+where
+    S: SessionStore<Value = CompressedValue>,
+    T: 'static + Clone + DeserializeOwned + Serialize + Send + Sync,
+{
+    type Value = T;
+
+    fn keyring(&self) -> &Keyring {
+        self.store.keyring()
+    }
+    fn key_name(&self) -> &str {
+        self.store.key_name()
+    }
+
+    async fn set(
+        &self,
+        prefix: Option<String>,
+        session_id: &Uuid,
+        session: &Session<Self::Value>,
+    ) -> Result<(), SessionError> {
+        let session = Session {
+            session_id: session.session_id,
+            created_at: session.created_at,
+            value: self.encode(&session.value)?,
+            max_age: session.max_age,
+            expires: session.expires,
+            idle_timeout: session.idle_timeout,
+            max_lifetime: session.max_lifetime,
+        };
+        self.store.set(prefix, session_id, &session).await
+    }
+
+    async fn get(&self, session_id: &Uuid) -> Result<Session<Self::Value>, SessionError> {
+        self.decode_session(self.store.get(session_id).await?)
+    }
+
+    async fn delete(&self, session_id: &Uuid) -> Result<(), SessionError> {
+        self.store.delete(session_id).await
+    }
+
+    async fn get_many(
+        &self,
+        session_ids: &[Uuid],
+    ) -> Result<Vec<Result<Session<Self::Value>, SessionError>>, SessionError> {
+        Ok(self
+            .store
+            .get_many(session_ids)
+            .await?
+            .into_iter()
+            .map(|session| self.decode_session(session?))
+            .collect())
+    }
+
+    fn get_from_headers(&self, headers: &HeaderMap) -> Result<Option<Session<Self::Value>>, SessionError> {
+        self.store
+            .get_from_headers(headers)?
+            .map(|session| self.decode_session(session))
+            .transpose()
+    }
+
+    async fn update(&self, session_id: &Uuid, value: &Self::Value) -> Result<(), SessionError> {
+        self.store.update(session_id, &self.encode(value)?).await
+    }
+
+    async fn modify(
+        &self,
+        session_id: &Uuid,
+        modify_fn: &mut (dyn for<'v> FnMut(&'v mut Self::Value) + Send),
+    ) -> Result<Session<Self::Value>, SessionError> {
+        // the wrapped store's closure cannot fail, so a value which cannot be decoded or encoded is left as it
+        // is and the error returned once the wrapped store is done
+        let mut result = Ok(());
+        let session = self
+            .store
+            .modify(session_id, &mut |encoded| {
+                result = self.decode(encoded).and_then(|mut value| {
+                    modify_fn(&mut value);
+                    *encoded = self.encode(&value)?;
+                    Ok(())
+                });
+            })
+            .await?;
+        result?;
+        self.decode_session(session)
+    }
+
+    async fn touch(&self, session_id: &Uuid, extend_by: Duration) -> Result<(), SessionError> {
+        self.store.touch(session_id, extend_by).await
+    }
+
+    async fn list_by_prefix(&self, prefix: &str) -> Result<Vec<Uuid>, SessionError> {
+        self.store.list_by_prefix(prefix).await
+    }
+
+    async fn delete_by_prefix(&self, prefix: &str) -> Result<(), SessionError> {
+        self.store.delete_by_prefix(prefix).await
+    }
+
+    async fn store_session_and_set_cookie(
+        &self,
+        response_headers: &mut HeaderMap,
+        cookie_config: CookieConfig<'_, Self::Value>,
+        prefix: Option<String>,
+    ) -> Result<(), SessionError> {
+        let value = self.encode(cookie_config.value)?;
+        let cookie_config = CookieConfig {
+            value: &value,
+            http_only: cookie_config.http_only,
+            secure: cookie_config.secure,
+            same_site: cookie_config.same_site,
+            domain: cookie_config.domain,
+            path: cookie_config.path,
+            max_age: cookie_config.max_age,
+            expires: cookie_config.expires,
+            idle_timeout: cookie_config.idle_timeout,
+            max_lifetime: cookie_config.max_lifetime,
+            payload: cookie_config.payload,
+        };
+        self.store
+            .store_session_and_set_cookie(response_headers, cookie_config, prefix)
+            .await
+    }
+
+    async fn rotate(&self, session_id: &Uuid, new_session_id: &Uuid) -> Result<Session<Self::Value>, SessionError> {
+        self.decode_session(self.store.rotate(session_id, new_session_id).await?)
+    }
+
+    async fn rotate_session(
+        &self,
+        response_headers: &mut HeaderMap,
+        cookie_config: CookieConfig<'_, ()>,
+        session_id: &Uuid,
+    ) -> Result<Session<Self::Value>, SessionError> {
+        let session = self
+            .store
+            .rotate_session(response_headers, cookie_config, session_id)
+            .await?;
+        self.decode_session(session)
+    }
+
+    async fn delete_session(
+        &self,
+        response_headers: &mut HeaderMap,
+        cookie_config: CookieConfig<'_, ()>,
+        session_id: Option<&Uuid>,
+    ) -> Result<(), SessionError> {
+        self.store
+            .delete_session(response_headers, cookie_config, session_id)
+            .await
+    }
+}
+
+/// wraps the store so that session values are encoded with `codec` and compressed with `compression` before
+/// they are passed to it
+pub fn compressed_store<S, T>(store: S, codec: Codec, compression: Compression) -> CompressedStore<S, T>
+where
+    S: SessionStore<Value = CompressedValue>,
+{
+    CompressedStore {
+        store,
+        codec,
+        compression,
+        _value: PhantomData,
+    }
+}
+
+#[cfg(all(test, feature = "memory-backend"))]
+mod tests {
+    use super::*;
+    use ::chrono::Utc;
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct Value {
+        tags: Vec<String>,
+    }
+
+    fn compression() -> Compression {
+        #[cfg(feature = "zstd-compression")]
+        return Compression::zstd(0);
+        #[cfg(not(feature = "zstd-compression"))]
+        return Compression::lz4();
+    }
+
+    fn large_value() -> Value {
+        Value {
+            tags: vec!["tag".into(); 1000],
+        }
+    }
+
+    fn session(value: Value) -> Session<Value> {
+        Session {
+            session_id: Uuid::nil(),
+            created_at: Utc::now().naive_utc(),
+            value,
+            max_age: None,
+            expires: None,
+            idle_timeout: None,
+            max_lifetime: None,
+        }
+    }
+
+    fn store() -> CompressedStore<MemoryStore<CompressedValue>, Value> {
+        let store = memory_store(MemoryStoreConfig::builder().key_name("sid").key("secret").build()).unwrap();
+        compressed_store(store, Codec::Json, compression())
+    }
+
+    #[test]
+    fn compressed_values_serialize_as_base64_in_json() {
+        let value = CompressedValue(vec![0, 1, 2, 255]);
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(json, format!("\"{}\"", BASE64.encode(&value.0)));
+        assert_eq!(serde_json::from_str::<CompressedValue>(&json).unwrap(), value);
+    }
+
+    #[tokio::test]
+    async fn values_are_compressed_in_the_wrapped_store() {
+        let store = store();
+        let session_id = Uuid::new_v4();
+        store
+            .set(Some("user".into()), &session_id, &session(large_value()))
+            .await
+            .unwrap();
+
+        let stored = store.inner().get(&session_id).await.unwrap();
+        let uncompressed = Codec::Json.encode(&EncodedValue { value: large_value() }).unwrap();
+        assert!(stored.value.0.len() < uncompressed.len());
+
+        let session = store.get(&session_id).await.unwrap();
+        assert_eq!(session.session_id, session_id);
+        assert_eq!(session.value, large_value());
+        assert_eq!(store.list_by_prefix("user").await.unwrap(), vec![session_id]);
+    }
+
+    #[tokio::test]
+    async fn values_below_the_threshold_are_stored_uncompressed() {
+        let store = store();
+        let session_id = Uuid::new_v4();
+        let value = Value {
+            tags: vec!["tag".into()],
+        };
+        store.set(None, &session_id, &session(value.clone())).await.unwrap();
+
+        let stored = store.inner().get(&session_id).await.unwrap();
+        assert_eq!(
+            stored.value.0,
+            Codec::Json.encode(&EncodedValue { value: &value }).unwrap()
+        );
+        assert_eq!(store.get(&session_id).await.unwrap().value, value);
+    }
+
+    #[tokio::test]
+    async fn modify_update_and_rotate_decode_and_encode_values() {
+        let store = store();
+        let session_id = Uuid::new_v4();
+        store.set(None, &session_id, &session(large_value())).await.unwrap();
+
+        let session = store
+            .modify(&session_id, &mut |value| value.tags.push("modified".into()))
+            .await
+            .unwrap();
+        assert_eq!(session.value.tags.last().unwrap(), "modified");
+        assert_eq!(store.get(&session_id).await.unwrap().value, session.value);
+
+        let updated = Value {
+            tags: vec!["updated".into()],
+        };
+        store.update(&session_id, &updated).await.unwrap();
+        assert_eq!(store.get(&session_id).await.unwrap().value, updated);
+
+        let new_session_id = Uuid::new_v4();
+        let session = store.rotate(&session_id, &new_session_id).await.unwrap();
+        assert_eq!(session.value, updated);
+        let sessions = store.get_many(&[session_id, new_session_id]).await.unwrap();
+        assert!(matches!(sessions[0], Err(SessionError::NotFound)));
+        assert_eq!(sessions[1].as_ref().unwrap().value, updated);
+    }
+
+    #[tokio::test]
+    async fn undecodable_values_are_codec_errors() {
+        let store = store();
+        let session_id = Uuid::new_v4();
+        let session = Session {
+            session_id,
+            created_at: Utc::now().naive_utc(),
+            value: CompressedValue(b"not encoded".to_vec()),
+            max_age: None,
+            expires: None,
+            idle_timeout: None,
+            max_lifetime: None,
+        };
+        store.inner().set(None, &session_id, &session).await.unwrap();
+
+        assert!(matches!(store.get(&session_id).await, Err(SessionError::Codec(_))));
+        assert!(matches!(
+            store.modify(&session_id, &mut |_| {}).await,
+            Err(SessionError::Codec(_))
+        ));
+        assert_eq!(store.inner().get(&session_id).await.unwrap().value, session.value);
+    }
+}
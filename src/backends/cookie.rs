@@ -0,0 +1,402 @@
+use crate::*;
+use ::chrono::{NaiveDateTime, Utc};
+use ::data_encoding::BASE64URL_NOPAD;
+use ::derivative::Derivative;
+use ::http::header::{HeaderMap, COOKIE, SET_COOKIE};
+use ::serde::{de::DeserializeOwned, Deserialize, Serialize};
+use ::std::collections::HashMap;
+use ::std::{marker::PhantomData, ops::Deref, sync::Arc};
+use ::typed_builder::TypedBuilder;
+use ::uuid::Uuid;
+
+/// version prefix of cookies holding an encrypted session, `c1:<nonce, ciphertext and tag>`
+const COOKIE_SESSION_PREFIX: &str = "c1:";
+
+/// largest `Set-Cookie` header browsers are required to accept, including the cookie's name and attributes,
+/// see https://www.rfc-editor.org/rfc/rfc6265#section-6.1
+const MAX_COOKIE_LEN: usize = 4096;
+
+const DEFAULT_MAX_COOKIES: usize = 4;
+
+#[derive(Clone, Copy, Derivative, TypedBuilder)]
+#[derivative(Debug)]
+pub struct CookieStoreConfig<KN, K> {
+    pub key_name: KN,
+    #[derivative(Debug = "ignore")]
+    pub key: K,
+    /// how many cookies a session may be split across before it is rejected as too large, defaults to four
+    ///
+    /// each cookie holds up to 4096 bytes, browsers accept at least 50 cookies per domain but every
+    /// cookie is sent with every request so large sessions are better kept in a server side store
+    #[builder(default, setter(strip_option))]
+    pub max_cookies: Option<usize>,
+    /// compresses sessions larger than the compression's threshold before they are encrypted, letting
+    /// larger sessions fit into the same number of cookies
+    #[builder(default, setter(strip_option))]
+    pub compression: Option<Compression>,
+}
+
+/// session store which keeps the whole session in the client's cookies rather than in a backend
+///
+/// sessions are serialized and encrypted with the keyring's primary key, then split across the cookies
+/// `<key_name>`, `<key_name>.1`, `<key_name>.2`, etc. when they do not fit into a single cookie
+///
+/// as nothing is kept server side, sessions cannot be updated, touched, rotated or listed by prefix, and a
+/// cookie which has been copied remains valid until it expires, a new session is issued by calling
+/// `store_session_and_set_cookie` again
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct CookieStore<T> {
+    key_name: String,
+    keyring: Arc<Keyring>,
+    max_cookies: usize,
+    compression: Option<Compression>,
+    #[derivative(Debug = "ignore")]
+    _value: PhantomData<T>,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CookieSession<S> {
+    id: Uuid,
+    expires_at: Option<NaiveDateTime>,
+    session: S,
+}
+
+impl<T> CookieStore<T> {
+    /// names of the cookies a session may be split across, in order
+    fn cookie_names(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.max_cookies).map(|index| match index {
+            0 => self.key_name.clone(),
+            index => format!("{}.{index}", self.key_name),
+        })
+    }
+}
+
+#[async_trait]
+impl<T> SessionStore for CookieStore<T>
+where
+    T: 'static + Clone + DeserializeOwned + Serialize + Send + Sync,
+{
+    type Value = T;
+
+    fn key_name(&self) -> &str {
+        &self.key_name
+    }
+    fn keyring(&self) -> &Keyring {
+        self.keyring.deref()
+    }
+
+    async fn set(&self, _: Option<String>, _: &Uuid, _: &Session<Self::Value>) -> Result<(), SessionError> {
+        Err(SessionError::Unsupported(
+            "storing cookie sessions outside of store_session_and_set_cookie",
+        ))
+    }
+
+    async fn get(&self, _: &Uuid) -> Result<Session<Self::Value>, SessionError> {
+        Err(SessionError::Unsupported(
+            "reading cookie sessions outside of request headers",
+        ))
+    }
+
+    /// there is nothing to delete server side, the session's cookies are removed by `delete_session`
+    async fn delete(&self, _: &Uuid) -> Result<(), SessionError> {
+        Ok(())
+    }
+
+    fn get_from_headers(&self, headers: &HeaderMap) -> Result<Option<Session<Self::Value>>, SessionError> {
+        let mut cookies = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|header| header.to_str().ok())
+            .flat_map(|cookie_str| {
+                cookie_str
+                    .split(';')
+                    .filter_map(|x| cookie::Cookie::parse_encoded(x.trim()).ok())
+            })
+            .map(|cookie| (cookie.name().to_string(), cookie.value().to_string()))
+            .collect::<HashMap<_, _>>();
+
+        let mut encoded = String::new();
+        for cookie_name in self.cookie_names() {
+            match cookies.remove(&cookie_name) {
+                Some(value) if !value.is_empty() => encoded.push_str(&value),
+                _ => break,
+            }
+        }
+        let Some(encoded) = encoded.strip_prefix(COOKIE_SESSION_PREFIX) else {
+            return Ok(None);
+        };
+
+        // cookies which cannot be decrypted were either tampered with or encrypted with a key which has
+        // since been removed from the keyring, and are treated the same as a missing session
+        let Some(sealed) = BASE64URL_NOPAD.decode(encoded.as_bytes()).ok() else {
+            return Ok(None);
+        };
+        let Some((plaintext, _)) = self.keyring.decrypt(self.key_name.as_bytes(), &sealed) else {
+            return Ok(None);
+        };
+
+        let CookieSession {
+            id,
+            expires_at,
+            mut session,
+        }: CookieSession<Session<T>> = Codec::decode(&plaintext)?;
+
+        if matches!(expires_at, Some(expires_at) if expires_at <= Utc::now().naive_utc()) {
+            return Ok(None);
+        }
+
+        session.session_id = id;
+        match session.is_expired() {
+            true => Ok(None),
+            false => Ok(Some(session)),
+        }
+    }
+
+    async fn store_session_and_set_cookie(
+        &self,
+        response_headers: &mut HeaderMap,
+        cookie_config: CookieConfig<'_, Self::Value>,
+        _: Option<String>,
+    ) -> Result<(), SessionError> {
+        let session = Session {
+            session_id: Uuid::new_v4(),
+            created_at: Utc::now().naive_utc(),
+            value: cookie_config.value.clone(),
+            max_age: cookie_config.max_age,
+            expires: cookie_config.expires,
+            idle_timeout: cookie_config.idle_timeout,
+            max_lifetime: cookie_config.max_lifetime,
+        };
+
+        let plaintext = Codec::Json.encode_compressed(
+            &CookieSession {
+                id: session.session_id,
+                expires_at: session.ttl().map(|ttl| session.created_at + ttl),
+                session: &session,
+            },
+            self.compression.as_ref(),
+        )?;
+        let sealed = self
+            .keyring
+            .encrypt(self.key_name.as_bytes(), &plaintext)
+            .map_err(|_| SessionError::cookie("unable to encrypt cookie session"))?;
+        let encoded = format!("{COOKIE_SESSION_PREFIX}{}", BASE64URL_NOPAD.encode(&sealed));
+
+        // every cookie a session may be split across is set so that the cookies of a previous, larger
+        // session are removed rather than appended to this session
+        let mut header_values = Vec::with_capacity(self.max_cookies);
+        let mut remaining = encoded.as_str();
+        for cookie_name in self.cookie_names() {
+            if remaining.is_empty() {
+                header_values.push(expired_cookie_header(&cookie_name, &cookie_config)?);
+                continue;
+            }
+            let attributes_len = set_cookie_header(&cookie_name, "", &cookie_config)?.len();
+            let chunk_len = MAX_COOKIE_LEN.saturating_sub(attributes_len).min(remaining.len());
+            if chunk_len == 0 {
+                return Err(SessionError::cookie(
+                    "cookie attributes leave no room for the cookie session",
+                ));
+            }
+            let (chunk, rest) = remaining.split_at(chunk_len);
+            header_values.push(set_cookie_header(&cookie_name, chunk, &cookie_config)?);
+            remaining = rest;
+        }
+        if !remaining.is_empty() {
+            return Err(SessionError::cookie(format!(
+                "cookie session is too large to be split across {} cookies",
+                self.max_cookies
+            )));
+        }
+
+        for header_value in header_values {
+            response_headers.append(SET_COOKIE, header_value);
+        }
+        Ok(())
+    }
+
+    async fn delete_session(
+        &self,
+        response_headers: &mut HeaderMap,
+        cookie_config: CookieConfig<'_, ()>,
+        _: Option<&Uuid>,
+    ) -> Result<(), SessionError> {
+        let header_values = self
+            .cookie_names()
+            .map(|cookie_name| expired_cookie_header(&cookie_name, &cookie_config))
+            .collect::<Result<Vec<_>, _>>()?;
+        for header_value in header_values {
+            response_headers.append(SET_COOKIE, header_value);
+        }
+        Ok(())
+    }
+}
+
+pub fn cookie_store<T, KN, K>(
+    CookieStoreConfig {
+        key_name,
+        key,
+        max_cookies,
+        compression,
+    }: CookieStoreConfig<KN, K>,
+) -> Result<CookieStore<T>, SessionError>
+where
+    KN: ToString,
+    K: Into<Keyring>,
+{
+    let max_cookies = max_cookies.unwrap_or(DEFAULT_MAX_COOKIES);
+    if max_cookies == 0 {
+        return Err(SessionError::config(
+            "cookie session store max cookies must be non-zero",
+        ));
+    }
+
+    Ok(CookieStore {
+        key_name: key_name.to_string(),
+        keyring: Arc::new(key.into()),
+        max_cookies,
+        compression,
+        _value: PhantomData,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::http::HeaderValue;
+
+    fn store(max_cookies: usize) -> CookieStore<String> {
+        cookie_store(
+            CookieStoreConfig::builder()
+                .key_name("sid")
+                .key("secret")
+                .max_cookies(max_cookies)
+                .build(),
+        )
+        .unwrap()
+    }
+
+    /// random hex, which cannot be compressed to much less than half its length
+    fn random_value(len: usize) -> String {
+        std::iter::repeat_with(|| Uuid::new_v4().as_simple().to_string())
+            .flat_map(|uuid| uuid.into_bytes())
+            .take(len)
+            .map(char::from)
+            .collect()
+    }
+
+    /// the request headers of a client which received the response headers
+    fn request_headers(response_headers: &HeaderMap) -> HeaderMap {
+        let cookies = response_headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|header| header.to_str().unwrap().split(';').next().unwrap())
+            .filter(|cookie| !cookie.ends_with('='))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(&cookies).unwrap());
+        headers
+    }
+
+    async fn set_cookie(store: &CookieStore<String>, value: &String) -> Result<HeaderMap, SessionError> {
+        let mut headers = HeaderMap::new();
+        store
+            .store_session_and_set_cookie(&mut headers, CookieConfig::new(value), None)
+            .await?;
+        Ok(headers)
+    }
+
+    #[tokio::test]
+    async fn small_sessions_fit_in_one_cookie() {
+        let store = store(4);
+        let value = "value".to_string();
+        let response_headers = set_cookie(&store, &value).await.unwrap();
+
+        // the remaining cookies are expired so that a previous larger session does not linger
+        assert_eq!(response_headers.get_all(SET_COOKIE).iter().count(), 4);
+        let headers = request_headers(&response_headers);
+        assert!(!headers[COOKIE].to_str().unwrap().contains("sid.1="));
+
+        let session = store.get_from_headers(&headers).unwrap().unwrap();
+        assert_eq!(session.value, value);
+    }
+
+    #[tokio::test]
+    async fn large_sessions_are_split_across_cookies() {
+        let store = store(4);
+        let value = random_value(6000);
+        let response_headers = set_cookie(&store, &value).await.unwrap();
+
+        for header in response_headers.get_all(SET_COOKIE) {
+            assert!(header.len() <= MAX_COOKIE_LEN);
+        }
+        let headers = request_headers(&response_headers);
+        assert!(headers[COOKIE].to_str().unwrap().contains("sid.1="));
+
+        let session = store.get_from_headers(&headers).unwrap().unwrap();
+        assert_eq!(session.value, value);
+    }
+
+    #[tokio::test]
+    async fn sessions_larger_than_max_cookies_are_rejected() {
+        let store = store(1);
+        let mut response_headers = HeaderMap::new();
+        let value = random_value(6000);
+        let result = store
+            .store_session_and_set_cookie(&mut response_headers, CookieConfig::new(&value), None)
+            .await;
+
+        assert!(matches!(result, Err(SessionError::Cookie(_))));
+        assert!(response_headers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn missing_chunks_and_tampered_cookies_are_no_session() {
+        let store = store(4);
+        let response_headers = set_cookie(&store, &random_value(6000)).await.unwrap();
+        let headers = request_headers(&response_headers);
+        let cookies = headers[COOKIE].to_str().unwrap();
+
+        let first_chunk_only = cookies.split("; ").next().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(first_chunk_only).unwrap());
+        assert!(store.get_from_headers(&headers).unwrap().is_none());
+
+        let tampered = cookies.replacen("c1:", "c1:A", 1);
+        headers.insert(COOKIE, HeaderValue::from_str(&tampered).unwrap());
+        assert!(store.get_from_headers(&headers).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn cookies_from_another_key_are_no_session() {
+        let response_headers = set_cookie(&store(4), &"value".to_string()).await.unwrap();
+        let other =
+            cookie_store::<String, _, _>(CookieStoreConfig::builder().key_name("sid").key("other secret").build())
+                .unwrap();
+        assert!(other
+            .get_from_headers(&request_headers(&response_headers))
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn delete_session_expires_every_cookie() {
+        let store = store(4);
+        let mut response_headers = HeaderMap::new();
+        store
+            .delete_session(&mut response_headers, CookieConfig::new(&()), None)
+            .await
+            .unwrap();
+
+        let headers = response_headers.get_all(SET_COOKIE).iter().collect::<Vec<_>>();
+        assert_eq!(headers.len(), 4);
+        for (header, cookie_name) in headers.into_iter().zip(store.cookie_names()) {
+            let header = header.to_str().unwrap();
+            assert!(header.starts_with(&format!("{cookie_name}=;")));
+            assert!(header.contains("Expires="));
+        }
+    }
+}
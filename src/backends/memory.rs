@@ -0,0 +1,457 @@
+use crate::*;
+use ::chrono::{NaiveDateTime, Utc};
+use ::derivative::Derivative;
+use ::serde::{de::DeserializeOwned, Serialize};
+use ::std::collections::{HashMap, HashSet};
+use ::std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use ::std::{ops::Deref, time::Duration};
+use ::typed_builder::TypedBuilder;
+use ::uuid::Uuid;
+
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Derivative, TypedBuilder)]
+#[derivative(Debug)]
+pub struct MemoryStoreConfig<KN, K> {
+    pub key_name: KN,
+    #[derivative(Debug = "ignore")]
+    pub key: K,
+    /// how often expired sessions are evicted in the background, defaults to one minute
+    #[builder(default, setter(strip_option))]
+    pub sweep_interval: Option<Duration>,
+}
+
+/// session store which keeps sessions in process memory, useful for local development,
+/// single node deployments and tests which should not depend on an external key value store
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct MemoryStore<T> {
+    key_name: String,
+    keyring: Arc<Keyring>,
+    #[derivative(Debug = "ignore")]
+    state: Arc<RwLock<MemoryStoreState<T>>>,
+}
+
+struct MemoryStoreState<T> {
+    sessions: HashMap<Uuid, MemoryStoreEntry<T>>,
+    prefixes: HashMap<String, HashSet<Uuid>>,
+}
+
+struct MemoryStoreEntry<T> {
+    session: Session<T>,
+    prefix: Option<String>,
+    expires_at: Option<NaiveDateTime>,
+}
+
+impl<T> MemoryStoreEntry<T> {
+    fn is_expired(&self, now: NaiveDateTime) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+impl<T> Default for MemoryStoreState<T> {
+    fn default() -> Self {
+        Self {
+            sessions: HashMap::default(),
+            prefixes: HashMap::default(),
+        }
+    }
+}
+
+impl<T> MemoryStoreState<T> {
+    fn remove(&mut self, session_id: &Uuid) -> Option<MemoryStoreEntry<T>> {
+        let entry = self.sessions.remove(session_id)?;
+        if let Some(prefix) = entry.prefix.as_ref() {
+            if let Some(session_ids) = self.prefixes.get_mut(prefix) {
+                session_ids.remove(session_id);
+                if session_ids.is_empty() {
+                    self.prefixes.remove(prefix);
+                }
+            }
+        }
+        Some(entry)
+    }
+
+    fn evict_expired(&mut self) {
+        let now = Utc::now().naive_utc();
+        let expired_session_ids = self
+            .sessions
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(session_id, _)| *session_id)
+            .collect::<Vec<_>>();
+        for session_id in expired_session_ids {
+            self.remove(&session_id);
+        }
+    }
+}
+
+impl<T> MemoryStore<T> {
+    fn read(&self) -> Result<RwLockReadGuard<'_, MemoryStoreState<T>>, SessionError> {
+        self.state
+            .read()
+            .map_err(|_| SessionError::backend("memory session store lock was poisoned"))
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, MemoryStoreState<T>>, SessionError> {
+        self.state
+            .write()
+            .map_err(|_| SessionError::backend("memory session store lock was poisoned"))
+    }
+}
+
+#[async_trait]
+impl<T> SessionStore for MemoryStore<T>
+where
+    T: 'static + Clone + DeserializeOwned + Serialize + Send + Sync,
+{
+    type Value = T;
+
+    fn key_name(&self) -> &str {
+        &self.key_name
+    }
+    fn keyring(&self) -> &Keyring {
+        self.keyring.deref()
+    }
+
+    async fn set(
+        &self,
+        prefix: Option<String>,
+        session_id: &Uuid,
+        session: &Session<Self::Value>,
+    ) -> Result<(), SessionError> {
+        let mut session = session.clone();
+        session.session_id = *session_id;
+        let expires_at = session.ttl().map(|ttl| Utc::now().naive_utc() + ttl);
+
+        let mut state = self.write()?;
+        state.remove(session_id);
+        if let Some(prefix) = prefix.as_ref() {
+            state.prefixes.entry(prefix.clone()).or_default().insert(*session_id);
+        }
+        state.sessions.insert(
+            *session_id,
+            MemoryStoreEntry {
+                session,
+                prefix,
+                expires_at,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn get(&self, session_id: &Uuid) -> Result<Session<Self::Value>, SessionError> {
+        let now = Utc::now().naive_utc();
+        {
+            let state = self.read()?;
+            match state.sessions.get(session_id) {
+                Some(entry) if !entry.is_expired(now) => return entry.session.clone().unexpired(),
+                Some(_) => {}
+                None => return Err(SessionError::NotFound),
+            }
+        }
+        // lazily evict the expired session rather than waiting for the next sweep
+        self.write()?.remove(session_id);
+        Err(SessionError::NotFound)
+    }
+
+    async fn delete(&self, session_id: &Uuid) -> Result<(), SessionError> {
+        self.write()?.remove(session_id);
+        Ok(())
+    }
+
+    async fn update(&self, session_id: &Uuid, value: &Self::Value) -> Result<(), SessionError> {
+        self.modify(session_id, &mut |session_value| *session_value = value.clone())
+            .await?;
+        Ok(())
+    }
+
+    /// modifies the session while holding the store's lock, so concurrent modifications are applied one after another
+    async fn modify(
+        &self,
+        session_id: &Uuid,
+        modify_fn: &mut (dyn for<'v> FnMut(&'v mut Self::Value) + Send),
+    ) -> Result<Session<Self::Value>, SessionError> {
+        let now = Utc::now().naive_utc();
+        let mut state = self.write()?;
+        match state.sessions.get_mut(session_id) {
+            Some(entry) if !entry.is_expired(now) => {
+                if entry.session.is_expired() {
+                    return Err(SessionError::Expired);
+                }
+                modify_fn(&mut entry.session.value);
+                Ok(entry.session.clone())
+            }
+            _ => Err(SessionError::NotFound),
+        }
+    }
+
+    async fn rotate(&self, session_id: &Uuid, new_session_id: &Uuid) -> Result<Session<Self::Value>, SessionError> {
+        let now = Utc::now().naive_utc();
+        let mut state = self.write()?;
+        let Some(mut entry) = state.remove(session_id).filter(|entry| !entry.is_expired(now)) else {
+            return Err(SessionError::NotFound);
+        };
+        // sessions past their maximum lifetime are removed rather than rotated
+        if entry.session.is_expired() {
+            return Err(SessionError::Expired);
+        }
+
+        entry.session.session_id = *new_session_id;
+        if let Some(prefix) = entry.prefix.as_ref() {
+            state
+                .prefixes
+                .entry(prefix.clone())
+                .or_default()
+                .insert(*new_session_id);
+        }
+        let session = entry.session.clone();
+        state.sessions.insert(*new_session_id, entry);
+
+        Ok(session)
+    }
+
+    async fn touch(&self, session_id: &Uuid, extend_by: chrono::Duration) -> Result<(), SessionError> {
+        let now = Utc::now().naive_utc();
+        let mut state = self.write()?;
+        match state.sessions.get_mut(session_id) {
+            Some(entry) if !entry.is_expired(now) => {
+                entry.expires_at = Some(now + extend_by);
+                Ok(())
+            }
+            _ => Err(SessionError::NotFound),
+        }
+    }
+
+    async fn list_by_prefix(&self, prefix: &str) -> Result<Vec<Uuid>, SessionError> {
+        let now = Utc::now().naive_utc();
+        let state = self.read()?;
+        let Some(session_ids) = state.prefixes.get(prefix) else {
+            return Ok(vec![]);
+        };
+        Ok(session_ids
+            .iter()
+            .filter(|session_id| matches!(state.sessions.get(session_id), Some(entry) if !entry.is_expired(now)))
+            .copied()
+            .collect())
+    }
+
+    async fn delete_by_prefix(&self, prefix: &str) -> Result<(), SessionError> {
+        let mut state = self.write()?;
+        if let Some(session_ids) = state.prefixes.remove(prefix) {
+            for session_id in session_ids {
+                state.sessions.remove(&session_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn memory_store<T, KN, K>(
+    MemoryStoreConfig {
+        key_name,
+        key,
+        sweep_interval,
+    }: MemoryStoreConfig<KN, K>,
+) -> Result<MemoryStore<T>, SessionError>
+where
+    T: 'static + Send + Sync,
+    KN: ToString,
+    K: Into<Keyring>,
+{
+    let key_name = key_name.to_string();
+    let sweep_interval = sweep_interval.unwrap_or(DEFAULT_SWEEP_INTERVAL);
+    if sweep_interval.is_zero() {
+        return Err(SessionError::config(
+            "memory session store sweep interval must be non-zero",
+        ));
+    }
+
+    let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+        SessionError::config(
+            "memory session store must be created from within a tokio runtime to run its background sweep",
+        )
+    })?;
+
+    let state = Arc::new(RwLock::new(MemoryStoreState::default()));
+    runtime.spawn(purge_periodically(
+        Arc::downgrade(&state),
+        sweep_interval,
+        |state: Arc<RwLock<MemoryStoreState<T>>>| async move {
+            if let Ok(mut state) = state.write() {
+                state.evict_expired();
+            }
+        },
+    ));
+
+    Ok(MemoryStore {
+        key_name,
+        keyring: Arc::new(key.into()),
+        state,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::chrono::Duration;
+
+    fn store() -> MemoryStore<String> {
+        memory_store(MemoryStoreConfig::builder().key_name("sid").key("secret").build()).unwrap()
+    }
+
+    fn session(value: &str) -> Session<String> {
+        Session {
+            session_id: Uuid::nil(),
+            created_at: Utc::now().naive_utc(),
+            value: value.to_string(),
+            max_age: None,
+            expires: None,
+            idle_timeout: None,
+            max_lifetime: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn get_returns_stored_sessions() {
+        let store = store();
+        let session_id = Uuid::new_v4();
+        store.set(None, &session_id, &session("value")).await.unwrap();
+
+        let session = store.get(&session_id).await.unwrap();
+        assert_eq!(session.session_id, session_id);
+        assert_eq!(session.value, "value");
+        assert!(matches!(store.get(&Uuid::new_v4()).await, Err(SessionError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_not_found_after_their_ttl() {
+        let store = store();
+        let session_id = Uuid::new_v4();
+        let session = Session {
+            max_age: Some(Duration::milliseconds(50)),
+            ..session("value")
+        };
+        store.set(Some("user".into()), &session_id, &session).await.unwrap();
+        assert!(store.get(&session_id).await.is_ok());
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(matches!(store.get(&session_id).await, Err(SessionError::NotFound)));
+        assert!(matches!(
+            store.touch(&session_id, Duration::hours(1)).await,
+            Err(SessionError::NotFound)
+        ));
+        assert!(store.list_by_prefix("user").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn touch_extends_the_ttl() {
+        let store = store();
+        let session_id = Uuid::new_v4();
+        let session = Session {
+            max_age: Some(Duration::milliseconds(50)),
+            ..session("value")
+        };
+        store.set(None, &session_id, &session).await.unwrap();
+        store.touch(&session_id, Duration::hours(1)).await.unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(store.get(&session_id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn sessions_past_their_lifetime_are_expired() {
+        let store = store();
+        let session_id = Uuid::new_v4();
+        let session = Session {
+            max_lifetime: Some(Duration::milliseconds(50)),
+            ..session("value")
+        };
+        store.set(None, &session_id, &session).await.unwrap();
+        // the ttl outlives the session's lifetime
+        store.touch(&session_id, Duration::hours(1)).await.unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(matches!(store.get(&session_id).await, Err(SessionError::Expired)));
+        assert!(matches!(
+            store.update(&session_id, &"updated".to_string()).await,
+            Err(SessionError::Expired)
+        ));
+        assert!(matches!(
+            store.rotate(&session_id, &Uuid::new_v4()).await,
+            Err(SessionError::Expired)
+        ));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_listed_and_deleted_by_prefix() {
+        let store = store();
+        let user_session_ids = [Uuid::new_v4(), Uuid::new_v4()];
+        for session_id in &user_session_ids {
+            store
+                .set(Some("user".into()), session_id, &session("user"))
+                .await
+                .unwrap();
+        }
+        let other_session_id = Uuid::new_v4();
+        store
+            .set(Some("other".into()), &other_session_id, &session("other"))
+            .await
+            .unwrap();
+
+        let mut session_ids = store.list_by_prefix("user").await.unwrap();
+        session_ids.sort();
+        let mut expected = user_session_ids.to_vec();
+        expected.sort();
+        assert_eq!(session_ids, expected);
+        assert!(store.list_by_prefix("missing").await.unwrap().is_empty());
+
+        store.delete_by_prefix("user").await.unwrap();
+        assert!(store.list_by_prefix("user").await.unwrap().is_empty());
+        for session_id in &user_session_ids {
+            assert!(matches!(store.get(session_id).await, Err(SessionError::NotFound)));
+        }
+        assert!(store.get(&other_session_id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rotate_moves_the_session_and_its_prefix() {
+        let store = store();
+        let session_id = Uuid::new_v4();
+        let new_session_id = Uuid::new_v4();
+        store
+            .set(Some("user".into()), &session_id, &session("value"))
+            .await
+            .unwrap();
+
+        let session = store.rotate(&session_id, &new_session_id).await.unwrap();
+        assert_eq!(session.session_id, new_session_id);
+        assert_eq!(session.value, "value");
+        assert!(matches!(store.get(&session_id).await, Err(SessionError::NotFound)));
+        assert_eq!(store.get(&new_session_id).await.unwrap().value, "value");
+        assert_eq!(store.list_by_prefix("user").await.unwrap(), vec![new_session_id]);
+        assert!(matches!(
+            store.rotate(&session_id, &Uuid::new_v4()).await,
+            Err(SessionError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn modify_applies_the_closure_to_the_stored_value() {
+        let store = store();
+        let session_id = Uuid::new_v4();
+        store.set(None, &session_id, &session("value")).await.unwrap();
+
+        let session = store
+            .modify(&session_id, &mut |value| value.push_str("-modified"))
+            .await
+            .unwrap();
+        assert_eq!(session.value, "value-modified");
+        assert_eq!(store.get(&session_id).await.unwrap().value, "value-modified");
+        assert!(matches!(
+            store.modify(&Uuid::new_v4(), &mut |_| {}).await,
+            Err(SessionError::NotFound)
+        ));
+    }
+}
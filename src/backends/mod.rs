@@ -1,6 +1,50 @@
+cfg_if! {
+    if #[cfg(any(feature = "lz4-compression", feature = "zstd-compression"))] {
+        mod compressed;
+        pub use compressed::*;
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "cookie-backend")] {
+        mod cookie;
+        pub use cookie::*;
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "memory-backend")] {
+        mod memory;
+        pub use memory::*;
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "postgres-backend")] {
+        mod postgres;
+        pub use postgres::*;
+    }
+}
+
+cfg_if! {
+    if #[cfg(any(feature = "memory-backend", feature = "postgres-backend", feature = "sqlite-backend"))] {
+        mod purge;
+        pub(crate) use purge::*;
+    }
+}
+
 cfg_if! {
     if #[cfg(feature = "redis-backend")] {
         mod redis;
+        mod redis_url;
         pub use redis::*;
+        pub use redis_url::*;
+    }
+}
+
+cfg_if! {
+    if #[cfg(feature = "sqlite-backend")] {
+        mod sqlite;
+        pub use sqlite::*;
     }
 }
@@ -0,0 +1,23 @@
+use ::std::future::Future;
+use ::std::sync::{Arc, Weak};
+use ::std::time::Duration;
+
+/// calls `purge` with the store's shared state every `purge_interval`, exiting once every handle to the store
+/// has been dropped, the state is only held for the duration of each purge so it does not keep the store alive
+pub(crate) async fn purge_periodically<S, F, Fut>(store: Weak<S>, purge_interval: Duration, mut purge: F)
+where
+    F: FnMut(Arc<S>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut interval = tokio::time::interval(purge_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let Some(store) = store.upgrade() else {
+            return;
+        };
+        purge(store).await;
+    }
+}
@@ -1,19 +1,23 @@
 use crate::*;
-use ::anyhow::Error;
+use ::chrono::Duration;
 use ::deadpool::managed::{self, Metrics, Object, Pool};
 use ::derivative::Derivative;
+use ::futures::FutureExt;
 use ::log::info;
-use ::redis_cluster_async::redis::{self, aio::ConnectionLike, cmd, RedisError};
-use ::ring::hmac::{Key, HMAC_SHA256};
+use ::redis_cluster_async::redis::{self, aio::ConnectionLike, cmd, Cmd, ErrorKind, RedisError};
 use ::serde::{de::DeserializeOwned, Serialize};
-use ::std::fmt::{Debug, Display};
+use ::std::collections::BTreeMap;
 use ::std::ops::{Deref, DerefMut};
+use ::std::path::Path;
+use ::std::sync::atomic::{AtomicU64, Ordering};
+use ::std::sync::Mutex;
+use ::std::time::Duration as StdDuration;
 use ::std::{marker::PhantomData, sync::Arc};
 use ::typed_builder::TypedBuilder;
 use ::url::Url;
 use ::uuid::Uuid;
 
-#[derive(Clone, Copy, Derivative, TypedBuilder)]
+#[derive(Clone, Derivative, TypedBuilder)]
 #[derivative(Debug)]
 pub struct RedisStoreConfig<KN, K, U, P> {
     pub key_name: KN,
@@ -21,17 +25,428 @@ pub struct RedisStoreConfig<KN, K, U, P> {
     pub username: Option<U>,
     #[derivative(Debug = "ignore")]
     pub password: Option<P>,
+    /// prepended to every key written by the store, e.g. `sess:{app}:`, so that sessions cannot collide with
+    /// other keys in a shared redis and can be scanned or flushed by pattern, defaults to no namespace
+    ///
+    /// on a cluster, a hash tag such as `{app}` places every session key in the same slot
+    #[builder(default, setter(into, strip_option))]
+    pub namespace: Option<String>,
+    /// format sessions are stored in, sessions stored with a different codec can still be read
+    #[builder(default)]
+    pub codec: Codec,
+    /// compresses sessions larger than the compression's threshold, compressed and uncompressed sessions
+    /// can be read side by side
+    #[builder(default, setter(strip_option))]
+    pub compression: Option<Compression>,
+    /// connects to redis over tls, `rediss://`, requires the `redis-tls` feature
+    #[builder(default, setter(strip_option))]
+    pub tls: Option<RedisStoreTlsConfig>,
+    /// size and timeouts of the store's connection pool
+    #[builder(default)]
+    pub pool: RedisStorePoolConfig,
+    /// how long a single redis command may take before it fails, the connection it was sent on is then
+    /// closed rather than returned to the pool, defaults to no timeout
+    #[builder(default, setter(strip_option))]
+    pub command_timeout: Option<StdDuration>,
 }
 
-#[derive(Clone, Copy, Debug, TypedBuilder)]
+/// connection pool settings of a redis store, settings which are not provided keep deadpool's defaults
+#[derive(Clone, Copy, Debug, Default, TypedBuilder)]
+pub struct RedisStorePoolConfig {
+    /// maximum number of open connections, defaults to four times the number of cpu cores
+    #[builder(default, setter(strip_option))]
+    pub max_size: Option<usize>,
+    /// how long a request waits for a connection when every connection is in use, defaults to no timeout
+    #[builder(default, setter(strip_option))]
+    pub wait_timeout: Option<StdDuration>,
+    /// how long opening a new connection may take, defaults to no timeout
+    #[builder(default, setter(strip_option))]
+    pub create_timeout: Option<StdDuration>,
+    /// how long checking that a pooled connection is still usable may take, defaults to no timeout
+    #[builder(default, setter(strip_option))]
+    pub recycle_timeout: Option<StdDuration>,
+    /// runtime the pool's timeouts run on, defaults to tokio
+    #[builder(default, setter(strip_option))]
+    pub runtime: Option<RedisStoreRuntime>,
+}
+
+pub use ::deadpool::Runtime as RedisStoreRuntime;
+
+fn pool<M, W>(manager: M, config: RedisStorePoolConfig) -> Result<Pool<M, W>, SessionError>
+where
+    M: managed::Manager,
+    W: From<Object<M>>,
+{
+    let mut builder = Pool::builder(manager)
+        .wait_timeout(config.wait_timeout)
+        .create_timeout(config.create_timeout)
+        .recycle_timeout(config.recycle_timeout)
+        .runtime(config.runtime.unwrap_or(RedisStoreRuntime::Tokio1));
+    if let Some(max_size) = config.max_size {
+        builder = builder.max_size(max_size);
+    }
+    builder.build().map_err(|err| SessionError::config(err.to_string()))
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, TypedBuilder)]
 pub struct RedisStoreNodeConfig<H> {
     pub host: H,
     pub port: Option<u16>,
     pub db: Option<u16>,
 }
 
+/// unix domain socket a standalone redis listens on, e.g. `/var/run/redis/redis.sock` of a sidecar
+#[derive(Clone, Copy, Debug, Eq, PartialEq, TypedBuilder)]
+pub struct RedisStoreSocketConfig<S> {
+    pub path: S,
+    #[builder(default, setter(strip_option))]
+    pub db: Option<u16>,
+}
+
+/// tls settings of a redis store, the server's certificate is verified against the system's root certificates
+/// unless ca certificates are provided
+#[derive(Clone, Default, Derivative, Eq, PartialEq, TypedBuilder)]
+#[derivative(Debug)]
+pub struct RedisStoreTlsConfig {
+    /// pem encoded bundle of ca certificates trusted in addition to the system's root certificates, e.g. the ca
+    /// of a managed redis which signs its certificates with a private ca
+    #[builder(default, setter(strip_option))]
+    pub ca_certs: Option<Vec<u8>>,
+    /// pem encoded certificate chain presented to redis servers which require client certificates, must be
+    /// provided along with `client_key`
+    #[builder(default, setter(strip_option))]
+    pub client_cert: Option<Vec<u8>>,
+    /// pem encoded pkcs #8 private key of the client certificate
+    #[derivative(Debug = "ignore")]
+    #[builder(default, setter(strip_option))]
+    pub client_key: Option<Vec<u8>>,
+    /// accepts any server certificate and hostname, only for development against self signed certificates
+    #[builder(default)]
+    pub insecure: bool,
+}
+
+impl RedisStoreTlsConfig {
+    /// whether connections need a tls connector of their own, connections which only verify the server against
+    /// the system's root certificates use redis' own tls support
+    fn has_certs(&self) -> bool {
+        self.ca_certs.is_some() || self.client_cert.is_some() || self.client_key.is_some()
+    }
+}
+
+/// sentinels which monitor the master sessions are stored on, the master's address is asked of the sentinels,
+/// in order, whenever a new connection is made, so that connections follow the master after a failover
+#[derive(Clone, Derivative, TypedBuilder)]
+#[derivative(Debug)]
+pub struct RedisStoreSentinelConfig<M, H> {
+    /// name the master is monitored under by the sentinels
+    pub master_name: M,
+    /// the sentinels' addresses, their `db` is ignored
+    pub sentinels: Vec<RedisStoreNodeConfig<H>>,
+    /// database selected on the master
+    #[builder(default, setter(strip_option))]
+    pub db: Option<u16>,
+    /// credentials for sentinels which require authentication, the store config's credentials are used
+    /// for the master
+    #[builder(default, setter(into, strip_option))]
+    pub sentinel_username: Option<String>,
+    #[derivative(Debug = "ignore")]
+    #[builder(default, setter(into, strip_option))]
+    pub sentinel_password: Option<String>,
+}
+
+/// client which connects to whichever node the sentinels currently report as the master
+pub struct SentinelClient {
+    master_name: String,
+    sentinels: Vec<redis::Client>,
+    username: Option<String>,
+    password: Option<String>,
+    db: Option<u16>,
+    tls: Option<RedisStoreTlsConfig>,
+}
+
+impl SentinelClient {
+    async fn connect_to_master(
+        &self,
+        connector: &RedisConnector,
+        sentinel: &redis::Client,
+    ) -> Result<redis::aio::Connection, RedisError> {
+        let mut sentinel_conn = connector.connect(sentinel).await?;
+        let (host, port): (String, u16) = cmd("SENTINEL")
+            .arg("get-master-addr-by-name")
+            .arg(&self.master_name)
+            .query_async::<_, Option<(String, u16)>>(&mut sentinel_conn)
+            .await?
+            .ok_or((ErrorKind::ResponseError, "sentinel does not monitor the master"))?;
+
+        let host = match host.contains(':') {
+            true => format!("[{host}]"),
+            false => host,
+        };
+        let path = self.db.map(|db| format!("/{db}"));
+        let url = url(
+            self.username.as_deref(),
+            self.password.as_deref(),
+            &host,
+            Some(port),
+            path.as_deref(),
+            self.tls.as_ref(),
+        )
+        .map_err(|err| {
+            RedisError::from((
+                ErrorKind::InvalidClientConfig,
+                "invalid master address",
+                err.to_string(),
+            ))
+        })?;
+
+        let mut conn = connector.connect(&redis::Client::open(url)?).await?;
+        // a sentinel may still report the previous master for a moment after a failover
+        if !is_master(&mut conn).await? {
+            return Err((
+                ErrorKind::ResponseError,
+                "sentinel reported a node which is not a master",
+            )
+                .into());
+        }
+        Ok(conn)
+    }
+}
+
+async fn is_master<C: ConnectionLike + Send>(conn: &mut C) -> Result<bool, RedisError> {
+    let role: Vec<redis::Value> = cmd("ROLE").query_async(conn).await?;
+    match role.first() {
+        Some(role) => Ok(redis::from_redis_value::<String>(role)? == "master"),
+        None => Ok(false),
+    }
+}
+
+/// opens connections with the tls connector built from a store's tls config, or with redis' own connection
+/// handling when the store does not need a tls connector of its own
+#[derive(Clone, Default)]
+pub struct RedisConnector {
+    #[cfg(feature = "redis-tls")]
+    tls: Option<tokio_native_tls::TlsConnector>,
+}
+
+impl RedisConnector {
+    #[cfg(feature = "redis-tls")]
+    fn new(tls: Option<&RedisStoreTlsConfig>) -> Result<Self, SessionError> {
+        let Some(tls) = tls.filter(|tls| tls.has_certs()) else {
+            return Ok(Self::default());
+        };
+
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(ca_certs) = &tls.ca_certs {
+            let ca_certs = native_tls::Certificate::stack_from_pem(ca_certs)
+                .map_err(|err| SessionError::config(format!("invalid redis ca certificates: {err}")))?;
+            for ca_cert in ca_certs {
+                builder.add_root_certificate(ca_cert);
+            }
+        }
+        match (&tls.client_cert, &tls.client_key) {
+            (Some(client_cert), Some(client_key)) => {
+                let identity = native_tls::Identity::from_pkcs8(client_cert, client_key)
+                    .map_err(|err| SessionError::config(format!("invalid redis client certificate: {err}")))?;
+                builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(SessionError::config(
+                    "redis client certificate and client key must be provided together",
+                ))
+            }
+        }
+        builder
+            .danger_accept_invalid_certs(tls.insecure)
+            .danger_accept_invalid_hostnames(tls.insecure);
+
+        let connector = builder
+            .build()
+            .map_err(|err| SessionError::config(format!("invalid redis tls config: {err}")))?;
+        Ok(Self {
+            tls: Some(connector.into()),
+        })
+    }
+
+    #[cfg(not(feature = "redis-tls"))]
+    fn new(tls: Option<&RedisStoreTlsConfig>) -> Result<Self, SessionError> {
+        match tls {
+            Some(_) => Err(SessionError::config("redis tls requires the `redis-tls` feature")),
+            None => Ok(Self::default()),
+        }
+    }
+
+    #[cfg(feature = "redis-tls")]
+    async fn tls_stream(
+        &self,
+        addr: &redis::ConnectionAddr,
+    ) -> Result<Option<tokio_native_tls::TlsStream<tokio::net::TcpStream>>, RedisError> {
+        let (Some(tls), redis::ConnectionAddr::TcpTls { host, port, .. }) = (&self.tls, addr) else {
+            return Ok(None);
+        };
+        let tcp = tokio::net::TcpStream::connect((host.as_str(), *port)).await?;
+        let stream = tls
+            .connect(host, tcp)
+            .await
+            .map_err(|err| RedisError::from((ErrorKind::IoError, "tls handshake failed", err.to_string())))?;
+        Ok(Some(stream))
+    }
+
+    async fn connect(&self, client: &redis::Client) -> Result<redis::aio::Connection, RedisError> {
+        #[cfg(feature = "redis-tls")]
+        if let Some(stream) = self.tls_stream(&client.get_connection_info().addr).await? {
+            let stream: std::pin::Pin<Box<dyn redis::aio::AsyncStream + Send + Sync>> = Box::pin(stream);
+            return redis::aio::Connection::new(&client.get_connection_info().redis, stream).await;
+        }
+        client.get_async_connection().await
+    }
+
+    async fn connect_multiplexed(
+        &self,
+        info: redis::ConnectionInfo,
+    ) -> Result<redis::aio::MultiplexedConnection, RedisError> {
+        #[cfg(feature = "redis-tls")]
+        if let Some(stream) = self.tls_stream(&info.addr).await? {
+            // redis panics when the stream closes while a new multiplexed connection authenticates, e.g. when
+            // the server rejects the client certificate, so the connection authenticates once its driver runs
+            let (mut conn, driver) = redis::aio::MultiplexedConnection::new(&Default::default(), stream).await?;
+            tokio::spawn(driver);
+            if let Some(password) = &info.redis.password {
+                cmd("AUTH")
+                    .arg(&info.redis.username)
+                    .arg(password)
+                    .query_async::<_, ()>(&mut conn)
+                    .await?;
+            }
+            if info.redis.db != 0 {
+                cmd("SELECT").arg(info.redis.db).query_async::<_, ()>(&mut conn).await?;
+            }
+            return Ok(conn);
+        }
+        redis::Client::open(info)?.get_multiplexed_tokio_connection().await
+    }
+}
+
+/// connectors of the cluster stores which need a tls connector of their own, keyed by the id of their client
+///
+/// nodes of a cluster are connected to by `redis_cluster_async`, which only passes a node's address and
+/// credentials on to its connections, so a client's id is passed along as a tag on the username of its nodes
+static CLUSTER_CONNECTORS: Mutex<BTreeMap<u64, RedisConnector>> = Mutex::new(BTreeMap::new());
+
+static NEXT_CLUSTER_CONNECTOR_ID: AtomicU64 = AtomicU64::new(0);
+
+/// prefix of the tag `<prefix><id>.` which precedes the username of the nodes of a cluster client with a
+/// connector of its own
+const CLUSTER_CONNECTOR_TAG: &str = "session-util-connector-";
+
+/// client of a redis cluster whose nodes are connected to with the connector of the client's store
+pub struct ClusterClient {
+    client: redis_cluster_async::Client,
+    connector_id: Option<u64>,
+}
+
+impl ClusterClient {
+    fn open(
+        nodes: Vec<Url>,
+        connector: &RedisConnector,
+        tls: Option<&RedisStoreTlsConfig>,
+    ) -> Result<Self, SessionError> {
+        let mut nodes = nodes
+            .into_iter()
+            .map(|url| redis::IntoConnectionInfo::into_connection_info(url.as_str()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| SessionError::config(err.to_string()))?;
+
+        let connector_id = tls.is_some_and(RedisStoreTlsConfig::has_certs).then(|| {
+            let connector_id = NEXT_CLUSTER_CONNECTOR_ID.fetch_add(1, Ordering::Relaxed);
+            for node in &mut nodes {
+                let username = node.redis.username.take().unwrap_or_default();
+                node.redis.username = Some(format!("{CLUSTER_CONNECTOR_TAG}{connector_id}.{username}"));
+            }
+            CLUSTER_CONNECTORS
+                .lock()
+                .unwrap()
+                .insert(connector_id, connector.clone());
+            connector_id
+        });
+
+        let client = redis_cluster_async::Client::open(nodes).map_err(|err| SessionError::config(err.to_string()));
+        Ok(Self {
+            client: client?,
+            connector_id,
+        })
+    }
+}
+
+impl Drop for ClusterClient {
+    fn drop(&mut self) {
+        if let Some(connector_id) = self.connector_id {
+            CLUSTER_CONNECTORS.lock().unwrap().remove(&connector_id);
+        }
+    }
+}
+
+/// removes the tag of a cluster client's connector from a node's username, returning the tagged connector
+fn untag_cluster_node(info: &mut redis::ConnectionInfo) -> Result<RedisConnector, RedisError> {
+    let Some((connector_id, username)) = info
+        .redis
+        .username
+        .as_deref()
+        .and_then(|username| username.strip_prefix(CLUSTER_CONNECTOR_TAG))
+        .and_then(|username| username.split_once('.'))
+    else {
+        return Ok(RedisConnector::default());
+    };
+
+    let connector = connector_id
+        .parse()
+        .ok()
+        .and_then(|connector_id: u64| CLUSTER_CONNECTORS.lock().unwrap().get(&connector_id).cloned())
+        .ok_or((ErrorKind::ClientError, "connector of the cluster client was dropped"))?;
+    info.redis.username = Some(username.to_owned()).filter(|username| !username.is_empty());
+    Ok(connector)
+}
+
+/// connection to a single node of a cluster, made with the connector of the cluster client
+#[derive(Clone)]
+pub struct ClusterNodeConnection(redis::aio::MultiplexedConnection);
+
+impl ConnectionLike for ClusterNodeConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> redis::RedisFuture<'a, redis::Value> {
+        self.0.req_packed_command(cmd)
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        self.0.req_packed_commands(cmd, offset, count)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.0.get_db()
+    }
+}
+
+impl redis_cluster_async::Connect for ClusterNodeConnection {
+    fn connect<'a, T>(info: T) -> redis::RedisFuture<'a, Self>
+    where
+        T: redis::IntoConnectionInfo + Send + 'a,
+    {
+        async move {
+            let mut info = info.into_connection_info()?;
+            let connector = untag_cluster_node(&mut info)?;
+            connector.connect_multiplexed(info).await.map(Self)
+        }
+        .boxed()
+    }
+}
+
 pub struct Manager<Client> {
     client: Client,
+    connector: RedisConnector,
 }
 
 pub struct Connection<Client>(Object<Manager<Client>>)
@@ -66,13 +481,96 @@ where
     }
 }
 
+impl<Client> From<Connection<Client>> for Object<Manager<Client>>
+where
+    Manager<Client>: managed::Manager,
+{
+    fn from(connection: Connection<Client>) -> Self {
+        connection.0
+    }
+}
+
+/// pooled connection which fails commands taking longer than the store's command timeout
+///
+/// the reply to a command which timed out may still arrive and would be read as the reply to the next
+/// command, so a connection on which a command timed out fails every further command, including the `UNWATCH`
+/// of an abandoned transaction, and is removed from the pool when it is dropped
+pub struct TimedConnection<M: managed::Manager> {
+    conn: Option<Object<M>>,
+    timeout: Option<StdDuration>,
+    timed_out: bool,
+}
+
+impl<M: managed::Manager> TimedConnection<M> {
+    fn conn(&mut self) -> &mut M::Type {
+        self.conn.as_mut().expect("connection is only taken when dropped")
+    }
+}
+
+impl<M: managed::Manager> Drop for TimedConnection<M> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take().filter(|_| self.timed_out) {
+            let _ = Object::take(conn);
+        }
+    }
+}
+
+impl<M> ConnectionLike for TimedConnection<M>
+where
+    M: managed::Manager,
+    M::Type: ConnectionLike + Send,
+{
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> redis::RedisFuture<'a, redis::Value> {
+        if self.timed_out {
+            return async { Err(command_timed_out()) }.boxed();
+        }
+        let Some(timeout) = self.timeout else {
+            return self.conn().req_packed_command(cmd);
+        };
+        async move {
+            let result = tokio::time::timeout(timeout, self.conn().req_packed_command(cmd)).await;
+            self.timed_out |= result.is_err();
+            result.map_err(|_| command_timed_out())?
+        }
+        .boxed()
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a redis::Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+        if self.timed_out {
+            return async { Err(command_timed_out()) }.boxed();
+        }
+        let Some(timeout) = self.timeout else {
+            return self.conn().req_packed_commands(cmd, offset, count);
+        };
+        async move {
+            let result = tokio::time::timeout(timeout, self.conn().req_packed_commands(cmd, offset, count)).await;
+            self.timed_out |= result.is_err();
+            result.map_err(|_| command_timed_out())?
+        }
+        .boxed()
+    }
+
+    fn get_db(&self) -> i64 {
+        self.conn.as_ref().map_or(0, |conn| conn.get_db())
+    }
+}
+
+fn command_timed_out() -> RedisError {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "redis command timed out").into()
+}
+
 #[async_trait]
 impl managed::Manager for Manager<redis::Client> {
     type Type = redis::aio::Connection;
     type Error = RedisError;
 
     async fn create(&self) -> Result<Self::Type, Self::Error> {
-        self.client.get_async_connection().await
+        self.connector.connect(&self.client).await
     }
 
     async fn recycle(&self, mut conn: &mut Self::Type, _: &Metrics) -> managed::RecycleResult<Self::Error> {
@@ -82,12 +580,12 @@ impl managed::Manager for Manager<redis::Client> {
 }
 
 #[async_trait]
-impl managed::Manager for Manager<redis_cluster_async::Client> {
-    type Type = redis_cluster_async::Connection;
+impl managed::Manager for Manager<ClusterClient> {
+    type Type = redis_cluster_async::Connection<ClusterNodeConnection>;
     type Error = RedisError;
 
     async fn create(&self) -> Result<Self::Type, Self::Error> {
-        self.client.get_connection().await
+        self.client.client.get_generic_connection().await
     }
 
     async fn recycle(&self, mut conn: &mut Self::Type, _: &Metrics) -> managed::RecycleResult<Self::Error> {
@@ -96,34 +594,273 @@ impl managed::Manager for Manager<redis_cluster_async::Client> {
     }
 }
 
+#[async_trait]
+impl managed::Manager for Manager<SentinelClient> {
+    type Type = redis::aio::Connection;
+    type Error = RedisError;
+
+    async fn create(&self) -> Result<Self::Type, Self::Error> {
+        let mut last_err = None;
+        for sentinel in &self.client.sentinels {
+            match self.client.connect_to_master(&self.connector, sentinel).await {
+                Ok(conn) => return Ok(conn),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| (ErrorKind::InvalidClientConfig, "no sentinels configured").into()))
+    }
+
+    /// connections to a node which has since been demoted to a replica are dropped, so that the next
+    /// connection is made to the new master
+    async fn recycle(&self, mut conn: &mut Self::Type, _: &Metrics) -> managed::RecycleResult<Self::Error> {
+        if !is_master(conn.deref_mut()).await? {
+            return Err(managed::RecycleError::StaticMessage(
+                "redis node is no longer the master",
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Derivative)]
 #[derivative(Debug)]
 pub struct RedisStore<T, Pool> {
     key_name: String,
-    key: Arc<Key>,
+    keyring: Arc<Keyring>,
+    namespace: String,
+    codec: Codec,
+    compression: Option<Compression>,
+    command_timeout: Option<StdDuration>,
+    /// cluster stores cannot run transactions across keys which belong to different slots
+    cluster: bool,
     #[derivative(Debug = "ignore")]
     pool: Pool,
     #[derivative(Debug = "ignore")]
     _value: PhantomData<T>,
 }
 
+/// number of times a transaction is retried when a watched key is modified before it commits
+const MAX_TRANSACTION_ATTEMPTS: usize = 8;
+
+/// extends the ttl of `KEYS[1]` to `ARGV[1]` milliseconds if it expires sooner, keys without a ttl are left as
+/// they are
+const EXTEND_TTL_SCRIPT: &str = r"
+local ttl = redis.call('PTTL', KEYS[1])
+if ttl >= 0 and ttl < tonumber(ARGV[1]) then
+    redis.call('PEXPIRE', KEYS[1], ARGV[1])
+end
+";
+
+impl<T, Pool> RedisStore<T, Pool> {
+    fn session_key(&self, session_id: &Uuid) -> String {
+        format!("{}{session_id}", self.namespace)
+    }
+
+    /// key holding the prefix a session was stored with, used to keep the prefix sets up to date
+    ///
+    /// the session key is the key's hash tag so that both keys belong to the same cluster slot, unless the
+    /// namespace already gives the session key a hash tag which the key then shares
+    fn session_prefix_key(&self, session_id: &Uuid) -> String {
+        let session_key = self.session_key(session_id);
+        match hash_tag(session_key.as_bytes()) {
+            Some(_) => format!("{session_key}:prefix"),
+            None => format!("{{{session_key}}}:prefix"),
+        }
+    }
+
+    fn prefix_key(&self, prefix: &str) -> String {
+        format!("{}{prefix}", self.namespace)
+    }
+
+    /// groups the indices of the provided keys by cluster slot, as a cluster can only read keys which
+    /// belong to different slots with separate commands, every key is placed in the same batch otherwise
+    fn slot_batches(&self, keys: &[String]) -> BTreeMap<u16, Vec<usize>> {
+        let mut batches: BTreeMap<u16, Vec<usize>> = BTreeMap::new();
+        for (index, key) in keys.iter().enumerate() {
+            let slot = if self.cluster { key_slot(key) } else { 0 };
+            batches.entry(slot).or_default().push(index);
+        }
+        batches
+    }
+
+    /// commands deleting the provided keys, with one DEL per cluster slot as a cluster cannot delete keys which
+    /// belong to different slots with a single command
+    fn delete_cmds(&self, keys: &[String]) -> Vec<Cmd> {
+        self.slot_batches(keys)
+            .into_values()
+            .map(|indices| {
+                let mut delete_keys = cmd("DEL");
+                for index in indices {
+                    delete_keys.arg(&keys[index]);
+                }
+                delete_keys
+            })
+            .collect()
+    }
+
+    /// commands deleting a session stored with the provided prefix
+    fn delete_session_cmds(&self, session_id: &Uuid, prefix: Option<&str>) -> Vec<Cmd> {
+        let mut cmds = self.delete_cmds(&[self.session_key(session_id), self.session_prefix_key(session_id)]);
+        if let Some(prefix) = prefix {
+            let mut remove_from_prefix = cmd("SREM");
+            remove_from_prefix
+                .arg(self.prefix_key(prefix))
+                .arg(session_id.to_string());
+            cmds.push(remove_from_prefix);
+        }
+        cmds
+    }
+
+    /// commands moving a session stored with the provided prefix and remaining ttl in milliseconds to a new id
+    fn rotate_session_cmds(
+        &self,
+        session_id: &Uuid,
+        new_session_id: &Uuid,
+        value: &[u8],
+        ttl: i64,
+        prefix: Option<&str>,
+    ) -> Vec<Cmd> {
+        // rename is not used because the new keys may belong to a different cluster slot
+        let mut set_session = cmd("SET");
+        set_session.arg(self.session_key(new_session_id)).arg(value);
+        if ttl > 0 {
+            set_session.arg("PX").arg(ttl);
+        }
+        let mut cmds = vec![set_session];
+        cmds.extend(self.delete_cmds(&[self.session_key(session_id), self.session_prefix_key(session_id)]));
+
+        if let Some(prefix) = prefix {
+            let prefix_key = self.prefix_key(prefix);
+
+            let mut set_session_prefix = cmd("SET");
+            set_session_prefix
+                .arg(self.session_prefix_key(new_session_id))
+                .arg(prefix);
+            if ttl > 0 {
+                set_session_prefix.arg("PX").arg(ttl);
+            }
+            let mut remove_from_prefix = cmd("SREM");
+            remove_from_prefix.arg(&prefix_key).arg(session_id.to_string());
+            let mut add_to_prefix = cmd("SADD");
+            add_to_prefix.arg(&prefix_key).arg(new_session_id.to_string());
+            cmds.extend([set_session_prefix, remove_from_prefix, add_to_prefix]);
+        }
+        cmds
+    }
+
+    /// commands deleting the sessions in a prefix set along with the set itself
+    fn delete_prefix_cmds(&self, prefix: &str, session_ids: &[Uuid]) -> Vec<Cmd> {
+        let mut keys = Vec::with_capacity(session_ids.len() * 2 + 1);
+        for session_id in session_ids {
+            keys.push(self.session_key(session_id));
+            keys.push(self.session_prefix_key(session_id));
+        }
+        keys.push(self.prefix_key(prefix));
+        self.delete_cmds(&keys)
+    }
+}
+
+impl<T, M, W> RedisStore<T, Pool<M, W>>
+where
+    M: managed::Manager,
+    M::Error: 'static + std::error::Error + Send + Sync,
+    W: From<Object<M>> + Into<Object<M>>,
+{
+    async fn connection(&self) -> Result<TimedConnection<M>, SessionError> {
+        let conn = self.pool.get().await.map_err(SessionError::backend)?;
+        Ok(TimedConnection {
+            conn: Some(conn.into()),
+            timeout: self.command_timeout,
+            timed_out: false,
+        })
+    }
+}
+
+/// watches the provided keys so that a following call to `commit` is aborted if any of them are modified
+async fn watch<C: ConnectionLike + Send>(conn: &mut C, cluster: bool, keys: &[&str]) -> Result<(), SessionError> {
+    if !cluster {
+        cmd("WATCH")
+            .arg(keys)
+            .query_async::<_, ()>(conn)
+            .await
+            .map_err(SessionError::backend)?;
+    }
+    Ok(())
+}
+
+/// unwatches the keys watched by `watch` when a transaction is abandoned before `commit`, which would otherwise
+/// stay watched on the pooled connection and abort the next transaction run on it
+async fn unwatch_on_err<C: ConnectionLike + Send, V>(
+    conn: &mut C,
+    cluster: bool,
+    result: Result<V, SessionError>,
+) -> Result<V, SessionError> {
+    if result.is_err() && !cluster {
+        // the original error is more useful than a failure to unwatch, a connection which cannot reach
+        // redis is discarded when it is next recycled
+        let _ = cmd("UNWATCH").query_async::<_, ()>(conn).await;
+    }
+    result
+}
+
+/// number of hash slots keys are distributed across on a cluster
+const CLUSTER_SLOTS: u16 = 16384;
+
+/// hash tag of a key, the non-empty part between its first `{` and the following `}`
+fn hash_tag(key: &[u8]) -> Option<&[u8]> {
+    key.iter()
+        .position(|byte| *byte == b'{')
+        .and_then(|open| {
+            let tag = &key[open + 1..];
+            tag.iter().position(|byte| *byte == b'}').map(|close| &tag[..close])
+        })
+        .filter(|tag| !tag.is_empty())
+}
+
+/// cluster slot a key belongs to, keys containing a hash tag such as `{app}` are placed by the tag alone
+fn key_slot(key: &str) -> u16 {
+    let key = key.as_bytes();
+    crc16::State::<crc16::XMODEM>::calculate(hash_tag(key).unwrap_or(key)) % CLUSTER_SLOTS
+}
+
+/// runs the provided commands in a MULTI/EXEC transaction, returning false if the transaction was
+/// aborted because a watched key was modified
+///
+/// keys on a cluster store may belong to different slots, so the commands are instead run one at a time,
+/// neither atomically nor guarded by `watch`, see `redis_store_cluster`
+async fn commit<C: ConnectionLike + Send>(conn: &mut C, cluster: bool, cmds: Vec<Cmd>) -> Result<bool, SessionError> {
+    if cluster {
+        for cmd in cmds {
+            cmd.query_async::<_, ()>(conn).await.map_err(SessionError::backend)?;
+        }
+        return Ok(true);
+    }
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for cmd in cmds {
+        pipe.add_command(cmd).ignore();
+    }
+    let committed: Option<()> = pipe.query_async(conn).await.map_err(SessionError::backend)?;
+    Ok(committed.is_some())
+}
+
 #[async_trait]
-impl<T, Manager, Connection, C> SessionStore for RedisStore<T, Pool<Manager, Connection>>
+impl<T, Manager, Connection> SessionStore for RedisStore<T, Pool<Manager, Connection>>
 where
     T: 'static + Clone + DeserializeOwned + Serialize + Send + Sync,
     Manager: 'static + managed::Manager + Send + Sync,
-    <Manager as managed::Manager>::Type: 'static + Send + Sync,
-    <Manager as managed::Manager>::Error: 'static + Debug + Display + Send + Sync,
-    Connection: 'static + From<Object<Manager>> + Send + Sync + DerefMut<Target = C>,
-    C: ConnectionLike + Send,
+    <Manager as managed::Manager>::Type: 'static + ConnectionLike + Send + Sync,
+    <Manager as managed::Manager>::Error: 'static + std::error::Error + Send + Sync,
+    Connection: 'static + From<Object<Manager>> + Into<Object<Manager>> + Send + Sync,
 {
     type Value = T;
 
     fn key_name(&self) -> &str {
         &self.key_name
     }
-    fn key(&self) -> &Key {
-        self.key.deref()
+    fn keyring(&self) -> &Keyring {
+        self.keyring.deref()
     }
 
     async fn set(
@@ -131,94 +868,458 @@ where
         prefix: Option<String>,
         session_id: &Uuid,
         session: &Session<Self::Value>,
-    ) -> Result<(), Error> {
-        let mut conn = self.pool.get().await.map_err(Error::msg)?;
-        let value = serde_json::to_string(&session).map_err(Error::msg)?;
-        let expires = if let Some(max_age) = session.max_age.as_ref() {
-            Some(format!("{}", max_age.num_seconds()))
-        } else {
-            session
-                .expires
-                .as_ref()
-                .map(|expires| format!("{}", (*expires - session.created_at).num_seconds()))
-        };
+    ) -> Result<(), SessionError> {
+        let mut conn = self.connection().await?;
+        let value = self.codec.encode_compressed(session, self.compression.as_ref())?;
+        // ttls are set in milliseconds, as a ttl truncated to 0 seconds would be rejected by redis
+        let ttl = session.ttl().map(|ttl| ttl.num_milliseconds().max(1));
 
-        let session_id = format!("{session_id}");
+        let session_key = self.session_key(session_id);
+        let session_prefix_key = self.session_prefix_key(session_id);
+        let prefix_key = prefix.as_deref().map(|prefix| self.prefix_key(prefix));
 
-        let args = match expires.as_ref() {
-            Some(expires) => vec![&*session_id, &*value, "EX", &**expires],
-            None => vec![&*session_id, &*value],
-        };
+        let mut watched_keys = vec![&*session_prefix_key];
+        watched_keys.extend(prefix_key.as_deref());
 
-        if let Some(prefix) = prefix {
-            cmd("SET")
-                .arg(&args)
-                .query_async(conn.deref_mut())
+        for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+            watch(&mut conn, self.cluster, &watched_keys).await?;
+
+            let prepared = async {
+                let previous_prefix: Option<String> = cmd("GET")
+                    .arg(&session_prefix_key)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(SessionError::backend)?;
+                let prefix_key_ttl: Option<i64> = match prefix_key.as_ref() {
+                    Some(prefix_key) => Some(
+                        cmd("PTTL")
+                            .arg(prefix_key)
+                            .query_async(&mut conn)
+                            .await
+                            .map_err(SessionError::backend)?,
+                    ),
+                    None => None,
+                };
+
+                let mut cmds = vec![];
+
+                let mut set_session = cmd("SET");
+                set_session.arg(&session_key).arg(&value);
+                if let Some(ttl) = ttl {
+                    set_session.arg("PX").arg(ttl);
+                }
+                cmds.push(set_session);
+
+                if let Some(previous_prefix) =
+                    previous_prefix.filter(|previous_prefix| Some(previous_prefix) != prefix.as_ref())
+                {
+                    let mut remove_from_previous_prefix = cmd("SREM");
+                    remove_from_previous_prefix
+                        .arg(self.prefix_key(&previous_prefix))
+                        .arg(session_id.to_string());
+                    cmds.push(remove_from_previous_prefix);
+                }
+
+                match (prefix.as_ref(), prefix_key.as_ref()) {
+                    (Some(prefix), Some(prefix_key)) => {
+                        let mut set_session_prefix = cmd("SET");
+                        set_session_prefix.arg(&session_prefix_key).arg(prefix);
+                        if let Some(ttl) = ttl {
+                            set_session_prefix.arg("PX").arg(ttl);
+                        }
+                        cmds.push(set_session_prefix);
+
+                        let mut add_to_prefix = cmd("SADD");
+                        add_to_prefix.arg(prefix_key).arg(session_id.to_string());
+                        cmds.push(add_to_prefix);
+
+                        // the prefix set lives at least as long as its longest lived session,
+                        // a ttl of -2 means the set does not exist yet and -1 means it never expires
+                        match (ttl, prefix_key_ttl) {
+                            (None, _) => {
+                                let mut persist_prefix = cmd("PERSIST");
+                                persist_prefix.arg(prefix_key);
+                                cmds.push(persist_prefix);
+                            }
+                            (Some(ttl), Some(prefix_key_ttl))
+                                if prefix_key_ttl == -2 || (0..ttl).contains(&prefix_key_ttl) =>
+                            {
+                                let mut expire_prefix = cmd("PEXPIRE");
+                                expire_prefix.arg(prefix_key).arg(ttl);
+                                cmds.push(expire_prefix);
+                            }
+                            _ => {}
+                        }
+                    }
+                    _ => {
+                        let mut delete_session_prefix = cmd("DEL");
+                        delete_session_prefix.arg(&session_prefix_key);
+                        cmds.push(delete_session_prefix);
+                    }
+                }
+                Ok::<_, SessionError>(cmds)
+            }
+            .await;
+            let cmds = unwatch_on_err(&mut conn, self.cluster, prepared).await?;
+
+            if commit(&mut conn, self.cluster, cmds).await? {
+                return Ok(());
+            }
+        }
+
+        Err(SessionError::Conflict("store session"))
+    }
+
+    async fn get(&self, session_id: &Uuid) -> Result<Session<Self::Value>, SessionError> {
+        let mut conn = self.connection().await?;
+        let value: Option<Vec<u8>> = cmd("GET")
+            .arg(&[&self.session_key(session_id)])
+            .query_async(&mut conn)
+            .await
+            .map_err(SessionError::backend)?;
+        let value = value.ok_or(SessionError::NotFound)?;
+        let mut session: Session<Self::Value> = Codec::decode(&value)?;
+        session.session_id = *session_id;
+        session.unexpired()
+    }
+
+    async fn get_many(
+        &self,
+        session_ids: &[Uuid],
+    ) -> Result<Vec<Result<Session<Self::Value>, SessionError>>, SessionError> {
+        if session_ids.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut conn = self.connection().await?;
+
+        // a cluster cannot read keys which belong to different slots with a single MGET,
+        // so the keys are read with one MGET per slot
+        let session_keys: Vec<String> = session_ids
+            .iter()
+            .map(|session_id| self.session_key(session_id))
+            .collect();
+        let mut values: Vec<Option<Vec<u8>>> = vec![None; session_keys.len()];
+        for indices in self.slot_batches(&session_keys).into_values() {
+            let mut get_sessions = cmd("MGET");
+            for index in &indices {
+                get_sessions.arg(&session_keys[*index]);
+            }
+            let batch_values: Vec<Option<Vec<u8>>> = get_sessions
+                .query_async(&mut conn)
                 .await
-                .map_err(Error::msg)?;
+                .map_err(SessionError::backend)?;
+            for (index, value) in indices.into_iter().zip(batch_values) {
+                values[index] = value;
+            }
+        }
+
+        Ok(session_ids
+            .iter()
+            .zip(values)
+            .map(|(session_id, value)| {
+                let value = value.ok_or(SessionError::NotFound)?;
+                let mut session: Session<Self::Value> = Codec::decode(&value)?;
+                session.session_id = *session_id;
+                session.unexpired()
+            })
+            .collect())
+    }
 
-            tokio::spawn(async move {
-                cmd("SADD")
-                    .arg(&[&prefix, &session_id])
-                    .query_async::<_, ()>(conn.deref_mut())
+    async fn delete(&self, session_id: &Uuid) -> Result<(), SessionError> {
+        let mut conn = self.connection().await?;
+        let session_prefix_key = self.session_prefix_key(session_id);
+
+        for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+            watch(&mut conn, self.cluster, &[&session_prefix_key]).await?;
+
+            let prepared = async {
+                let prefix: Option<String> = cmd("GET")
+                    .arg(&session_prefix_key)
+                    .query_async(&mut conn)
                     .await
-                    .map_err(Error::msg)?;
-                Ok::<_, Error>(())
-            });
-        } else {
-            cmd("SET")
-                .arg(&args)
-                .query_async::<_, ()>(conn.deref_mut())
+                    .map_err(SessionError::backend)?;
+
+                Ok::<_, SessionError>(self.delete_session_cmds(session_id, prefix.as_deref()))
+            }
+            .await;
+            let cmds = unwatch_on_err(&mut conn, self.cluster, prepared).await?;
+
+            if commit(&mut conn, self.cluster, cmds).await? {
+                return Ok(());
+            }
+        }
+
+        Err(SessionError::Conflict("delete session"))
+    }
+
+    async fn update(&self, session_id: &Uuid, value: &Self::Value) -> Result<(), SessionError> {
+        self.modify(session_id, &mut |session_value| *session_value = value.clone())
+            .await?;
+        Ok(())
+    }
+
+    async fn modify(
+        &self,
+        session_id: &Uuid,
+        modify_fn: &mut (dyn for<'v> FnMut(&'v mut Self::Value) + Send),
+    ) -> Result<Session<Self::Value>, SessionError> {
+        let mut conn = self.connection().await?;
+        let session_key = self.session_key(session_id);
+
+        for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+            watch(&mut conn, self.cluster, &[&session_key]).await?;
+
+            let prepared = async {
+                let stored: Option<Vec<u8>> = cmd("GET")
+                    .arg(&session_key)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(SessionError::backend)?;
+                let Some(stored) = stored else {
+                    return Err(SessionError::NotFound);
+                };
+                let mut session: Session<Self::Value> = Codec::decode(&stored)?;
+                session.session_id = *session_id;
+                let mut session = session.unexpired()?;
+                modify_fn(&mut session.value);
+                let updated = self.codec.encode_compressed(&session, self.compression.as_ref())?;
+
+                // KEEPTTL retains the remaining ttl and XX prevents recreating a session which expired in the meantime
+                let mut set_session = cmd("SET");
+                set_session.arg(&session_key).arg(updated).arg("XX").arg("KEEPTTL");
+                Ok::<_, SessionError>((session, vec![set_session]))
+            }
+            .await;
+            let (session, cmds) = unwatch_on_err(&mut conn, self.cluster, prepared).await?;
+
+            if commit(&mut conn, self.cluster, cmds).await? {
+                return Ok(session);
+            }
+        }
+
+        Err(SessionError::Conflict("modify session"))
+    }
+
+    async fn rotate(&self, session_id: &Uuid, new_session_id: &Uuid) -> Result<Session<Self::Value>, SessionError> {
+        let mut conn = self.connection().await?;
+
+        let session_key = self.session_key(session_id);
+        let session_prefix_key = self.session_prefix_key(session_id);
+
+        for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+            watch(&mut conn, self.cluster, &[&session_key, &session_prefix_key]).await?;
+
+            let prepared = async {
+                let value: Option<Vec<u8>> = cmd("GET")
+                    .arg(&session_key)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(SessionError::backend)?;
+                let Some(value) = value else {
+                    return Err(SessionError::NotFound);
+                };
+                let ttl: i64 = cmd("PTTL")
+                    .arg(&session_key)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(SessionError::backend)?;
+                let prefix: Option<String> = cmd("GET")
+                    .arg(&session_prefix_key)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(SessionError::backend)?;
+
+                let mut session: Session<Self::Value> = Codec::decode(&value)?;
+                session.session_id = *new_session_id;
+                let session = session.unexpired()?;
+
+                let cmds = self.rotate_session_cmds(session_id, new_session_id, &value, ttl, prefix.as_deref());
+                Ok::<_, SessionError>((session, cmds))
+            }
+            .await;
+            let (session, cmds) = unwatch_on_err(&mut conn, self.cluster, prepared).await?;
+
+            if commit(&mut conn, self.cluster, cmds).await? {
+                return Ok(session);
+            }
+        }
+
+        Err(SessionError::Conflict("rotate session"))
+    }
+
+    async fn touch(&self, session_id: &Uuid, extend_by: Duration) -> Result<(), SessionError> {
+        let mut conn = self.connection().await?;
+        // a ttl truncated to 0 seconds would expire the session immediately
+        let ttl = extend_by.num_milliseconds().max(1);
+
+        let session_key = self.session_key(session_id);
+        let session_prefix_key = self.session_prefix_key(session_id);
+
+        // sessions are touched on every request when using sliding expiration, so rather than running a
+        // transaction the keys are expired directly, in a single round trip unless they are split across slots
+        let (touched, prefix): (bool, Option<String>) =
+            if self.cluster && key_slot(&session_key) != key_slot(&session_prefix_key) {
+                let touched = cmd("PEXPIRE")
+                    .arg(&session_key)
+                    .arg(ttl)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(SessionError::backend)?;
+                let prefix = match touched {
+                    true => {
+                        let (prefix,): (Option<String>,) = redis::pipe()
+                            .cmd("PEXPIRE")
+                            .arg(&session_prefix_key)
+                            .arg(ttl)
+                            .ignore()
+                            .cmd("GET")
+                            .arg(&session_prefix_key)
+                            .query_async(&mut conn)
+                            .await
+                            .map_err(SessionError::backend)?;
+                        prefix
+                    }
+                    false => None,
+                };
+                (touched, prefix)
+            } else {
+                redis::pipe()
+                    .cmd("PEXPIRE")
+                    .arg(&session_key)
+                    .arg(ttl)
+                    .cmd("PEXPIRE")
+                    .arg(&session_prefix_key)
+                    .arg(ttl)
+                    .ignore()
+                    .cmd("GET")
+                    .arg(&session_prefix_key)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(SessionError::backend)?
+            };
+        if !touched {
+            return Err(SessionError::NotFound);
+        }
+
+        // the prefix set lives at least as long as its longest lived session, its ttl is compared and
+        // extended by a script as the set may be shared with sessions touched concurrently, EVAL is used
+        // rather than EVALSHA as a cluster would load the script on an arbitrary node
+        if let Some(prefix) = prefix {
+            cmd("EVAL")
+                .arg(EXTEND_TTL_SCRIPT)
+                .arg(1)
+                .arg(self.prefix_key(&prefix))
+                .arg(ttl)
+                .query_async::<_, ()>(&mut conn)
                 .await
-                .map_err(Error::msg)?;
+                .map_err(SessionError::backend)?;
         }
 
         Ok(())
     }
 
-    async fn get(&self, session_id: &Uuid) -> Result<Session<Self::Value>, Error> {
-        let mut conn = self.pool.get().await.map_err(Error::msg)?;
-        let value: String = cmd("GET")
-            .arg(&[&format!("{session_id}")])
-            .query_async(conn.deref_mut())
+    async fn list_by_prefix(&self, prefix: &str) -> Result<Vec<Uuid>, SessionError> {
+        let mut conn = self.connection().await?;
+        let prefix_key = self.prefix_key(prefix);
+
+        let members: Vec<String> = cmd("SMEMBERS")
+            .arg(&prefix_key)
+            .query_async(&mut conn)
             .await
-            .map_err(Error::msg)?;
-        let mut session: Session<Self::Value> = serde_json::from_str(&value).map_err(Error::msg)?;
-        session.session_id = *session_id;
-        Ok(session)
+            .map_err(SessionError::backend)?;
+
+        let mut member_ids = Vec::with_capacity(members.len());
+        let mut expired_members = vec![];
+        for member in members {
+            match Uuid::parse_str(&member) {
+                Ok(session_id) => member_ids.push((member, session_id)),
+                Err(_) => expired_members.push(member),
+            }
+        }
+
+        // the sessions are checked with one pipeline per cluster slot, or a single pipeline on other stores
+        let session_keys: Vec<String> = member_ids
+            .iter()
+            .map(|(_, session_id)| self.session_key(session_id))
+            .collect();
+        let mut exists = vec![false; session_keys.len()];
+        for indices in self.slot_batches(&session_keys).into_values() {
+            let mut pipe = redis::pipe();
+            for index in &indices {
+                pipe.cmd("EXISTS").arg(&session_keys[*index]);
+            }
+            let batch_exists: Vec<bool> = pipe.query_async(&mut conn).await.map_err(SessionError::backend)?;
+            for (index, session_exists) in indices.into_iter().zip(batch_exists) {
+                exists[index] = session_exists;
+            }
+        }
+
+        // members whose session has expired are pruned from the prefix set
+        let mut session_ids = Vec::with_capacity(member_ids.len());
+        for ((member, session_id), session_exists) in member_ids.into_iter().zip(exists) {
+            match session_exists {
+                true => session_ids.push(session_id),
+                false => expired_members.push(member),
+            }
+        }
+
+        if !expired_members.is_empty() {
+            cmd("SREM")
+                .arg(&prefix_key)
+                .arg(&expired_members)
+                .query_async::<_, ()>(&mut conn)
+                .await
+                .map_err(SessionError::backend)?;
+        }
+
+        Ok(session_ids)
     }
 
-    async fn delete(&self, session_id: &Uuid) -> Result<(), Error> {
-        let mut conn = self.pool.get().await.map_err(Error::msg)?;
-        cmd("DEL")
-            .arg(&[&format!("{session_id}")])
-            .query_async::<_, ()>(conn.deref_mut())
-            .await
-            .map_err(Error::msg)?;
-        Ok(())
+    async fn delete_by_prefix(&self, prefix: &str) -> Result<(), SessionError> {
+        let mut conn = self.connection().await?;
+        let prefix_key = self.prefix_key(prefix);
+
+        for _ in 0..MAX_TRANSACTION_ATTEMPTS {
+            watch(&mut conn, self.cluster, &[&prefix_key]).await?;
+
+            let prepared = async {
+                let members: Vec<String> = cmd("SMEMBERS")
+                    .arg(&prefix_key)
+                    .query_async(&mut conn)
+                    .await
+                    .map_err(SessionError::backend)?;
+
+                let session_ids: Vec<Uuid> = members
+                    .iter()
+                    .filter_map(|member| Uuid::parse_str(member).ok())
+                    .collect();
+                Ok::<_, SessionError>(self.delete_prefix_cmds(prefix, &session_ids))
+            }
+            .await;
+            let cmds = unwatch_on_err(&mut conn, self.cluster, prepared).await?;
+
+            if commit(&mut conn, self.cluster, cmds).await? {
+                return Ok(());
+            }
+        }
+
+        Err(SessionError::Conflict("delete sessions"))
     }
 }
 
 pub async fn redis_store_standalone<T, KN, K, U, P, H>(
-    RedisStoreConfig {
-        key_name,
-        key,
-        username,
-        password,
-    }: RedisStoreConfig<KN, K, U, P>,
+    config: RedisStoreConfig<KN, K, U, P>,
     RedisStoreNodeConfig { host, port, db }: RedisStoreNodeConfig<H>,
-) -> Result<RedisStore<T, Pool<Manager<redis::Client>, Connection<redis::Client>>>, Error>
+) -> Result<RedisStore<T, Pool<Manager<redis::Client>, Connection<redis::Client>>>, SessionError>
 where
     KN: ToString,
-    K: ToString,
+    K: Into<Keyring>,
     U: ToString,
     P: ToString,
     H: ToString,
 {
-    let key_name = key_name.to_string();
-    let key = key.to_string();
-    let username = username.as_ref().map(ToString::to_string);
-    let password = password.as_ref().map(ToString::to_string);
+    let username = config.username.as_ref().map(ToString::to_string);
+    let password = config.password.as_ref().map(ToString::to_string);
     let host = host.to_string();
 
     let path = db.map(|db| format!("/{db}"));
@@ -229,7 +1330,7 @@ where
         &host,
         port,
         path.as_deref(),
-        None,
+        config.tls.as_ref(),
     )?;
     let safe_url = safe_url(
         username.as_deref(),
@@ -237,44 +1338,120 @@ where
         &host,
         port,
         path.as_deref(),
-        None,
+        config.tls.as_ref(),
     )?;
 
     info!("connecting to redis session stores at {safe_url}");
 
-    let client = redis::Client::open(url)?;
+    let client = redis::Client::open(url).map_err(|err| SessionError::config(err.to_string()))?;
+    redis_store_client(config, client).await
+}
+
+/// creates a standalone redis store connected through a unix domain socket, e.g. to a redis sidecar, which
+/// behaves exactly like a store created by `redis_store_standalone`
+pub async fn redis_store_socket<T, KN, K, U, P, S>(
+    config: RedisStoreConfig<KN, K, U, P>,
+    RedisStoreSocketConfig { path, db }: RedisStoreSocketConfig<S>,
+) -> Result<RedisStore<T, Pool<Manager<redis::Client>, Connection<redis::Client>>>, SessionError>
+where
+    KN: ToString,
+    K: Into<Keyring>,
+    U: ToString,
+    P: ToString,
+    S: AsRef<Path>,
+{
+    if config.tls.is_some() {
+        return Err(SessionError::config(
+            "redis tls is not supported over unix domain sockets",
+        ));
+    }
+
+    let path = path.as_ref().to_path_buf();
+    info!("connecting to redis session stores at unix socket {}", path.display());
+
+    let client = redis::Client::open(redis::ConnectionInfo {
+        addr: redis::ConnectionAddr::Unix(path),
+        redis: redis::RedisConnectionInfo {
+            db: db.unwrap_or_default().into(),
+            username: config.username.as_ref().map(ToString::to_string),
+            password: config.password.as_ref().map(ToString::to_string),
+        },
+    })
+    .map_err(|err| SessionError::config(err.to_string()))?;
+    redis_store_client(config, client).await
+}
+
+/// creates a store whose connections are made by the client, which is already configured with the config's
+/// credentials and node
+async fn redis_store_client<T, KN, K, U, P>(
+    RedisStoreConfig {
+        key_name,
+        key,
+        namespace,
+        codec,
+        compression,
+        tls,
+        pool: pool_config,
+        command_timeout,
+        ..
+    }: RedisStoreConfig<KN, K, U, P>,
+    client: redis::Client,
+) -> Result<RedisStore<T, Pool<Manager<redis::Client>, Connection<redis::Client>>>, SessionError>
+where
+    KN: ToString,
+    K: Into<Keyring>,
+{
+    let connector = RedisConnector::new(tls.as_ref())?;
 
-    let pool = Pool::builder(Manager { client }).build().map_err(Error::msg)?;
+    let pool = pool(Manager { client, connector }, pool_config)?;
 
     // confirm a connection can be made
-    pool.get().await.map_err(Error::msg)?;
+    pool.get().await.map_err(SessionError::backend)?;
 
     Ok(RedisStore {
-        key_name,
-        key: Arc::new(Key::new(HMAC_SHA256, key.as_bytes())),
+        key_name: key_name.to_string(),
+        keyring: Arc::new(key.into()),
+        namespace: namespace.unwrap_or_default(),
+        codec,
+        compression,
+        command_timeout,
+        cluster: false,
         _value: PhantomData,
         pool,
     })
 }
 
+/// creates a store connected to a redis cluster
+///
+/// a session's own keys share a hash slot, but its prefix set generally belongs to a different slot, which a
+/// transaction cannot span, so keys in different slots are deleted with separate commands and the commands of
+/// `set`, `delete`, `update`, `modify`, `rotate` and `delete_by_prefix` are run one at a time on a cluster,
+/// without the atomicity or conflict detection of standalone and sentinel stores: a failure part way through can
+/// leave a session missing from its prefix set or stored under both ids when rotated, and of two concurrent
+/// writes to the same session the last one wins
 pub async fn redis_store_cluster<T, KN, K, U, P, H>(
     RedisStoreConfig {
         key_name,
         key,
         username,
         password,
+        namespace,
+        codec,
+        compression,
+        tls,
+        pool: pool_config,
+        command_timeout,
     }: RedisStoreConfig<KN, K, U, P>,
     node_configs: impl IntoIterator<Item = RedisStoreNodeConfig<H>>,
-) -> Result<RedisStore<T, Pool<Manager<redis_cluster_async::Client>, Connection<redis_cluster_async::Client>>>, Error>
+) -> Result<RedisStore<T, Pool<Manager<ClusterClient>, Connection<ClusterClient>>>, SessionError>
 where
     KN: ToString,
-    K: ToString,
+    K: Into<Keyring>,
     U: ToString,
     P: ToString,
     H: ToString,
 {
     let key_name = key_name.to_string();
-    let key = key.to_string();
     let username = username.as_ref().map(ToString::to_string);
     let password = password.as_ref().map(ToString::to_string);
 
@@ -290,7 +1467,7 @@ where
                 &host,
                 port,
                 path.as_deref(),
-                None,
+                tls.as_ref(),
             )?;
             let safe_url = safe_url(
                 username.as_deref(),
@@ -298,16 +1475,16 @@ where
                 &host,
                 port,
                 path.as_deref(),
-                None,
+                tls.as_ref(),
             )?;
             Ok((url, safe_url))
         })
-        .collect::<Result<Vec<_>, Error>>()?
+        .collect::<Result<Vec<_>, SessionError>>()?
         .into_iter()
         .unzip();
 
     if urls.is_empty() {
-        return Err(Error::msg("no node config provided for cluster redis store"));
+        return Err(SessionError::config("no node config provided for cluster redis store"));
     }
 
     info!("connecting to redis session stores at:");
@@ -315,16 +1492,120 @@ where
         info!("- {safe_url}");
     }
 
-    let client = redis_cluster_async::Client::open(urls)?;
+    let connector = RedisConnector::new(tls.as_ref())?;
+    let client = ClusterClient::open(urls, &connector, tls.as_ref())?;
 
-    let pool = Pool::builder(Manager { client }).build().map_err(Error::msg)?;
+    let pool = pool(Manager { client, connector }, pool_config)?;
 
     // confirm a connection can be made
-    pool.get().await.map_err(Error::msg)?;
+    pool.get().await.map_err(SessionError::backend)?;
 
     Ok(RedisStore {
         key_name,
-        key: Arc::new(Key::new(HMAC_SHA256, key.as_bytes())),
+        keyring: Arc::new(key.into()),
+        namespace: namespace.unwrap_or_default(),
+        codec,
+        compression,
+        command_timeout,
+        cluster: true,
+        _value: PhantomData,
+        pool,
+    })
+}
+
+pub async fn redis_store_sentinel<T, KN, K, U, P, M, H>(
+    RedisStoreConfig {
+        key_name,
+        key,
+        username,
+        password,
+        namespace,
+        codec,
+        compression,
+        tls,
+        pool: pool_config,
+        command_timeout,
+    }: RedisStoreConfig<KN, K, U, P>,
+    RedisStoreSentinelConfig {
+        master_name,
+        sentinels,
+        db,
+        sentinel_username,
+        sentinel_password,
+    }: RedisStoreSentinelConfig<M, H>,
+) -> Result<RedisStore<T, Pool<Manager<SentinelClient>, Connection<SentinelClient>>>, SessionError>
+where
+    KN: ToString,
+    K: Into<Keyring>,
+    U: ToString,
+    P: ToString,
+    M: ToString,
+    H: ToString,
+{
+    let key_name = key_name.to_string();
+    let master_name = master_name.to_string();
+
+    let (sentinels, safe_urls): (Vec<_>, Vec<_>) = sentinels
+        .into_iter()
+        .map(|RedisStoreNodeConfig { host, port, .. }| {
+            let host = host.to_string();
+            let url = url(
+                sentinel_username.as_deref(),
+                sentinel_password.as_deref(),
+                &host,
+                port,
+                None,
+                tls.as_ref(),
+            )?;
+            let safe_url = safe_url(
+                sentinel_username.as_deref(),
+                sentinel_password.as_deref(),
+                &host,
+                port,
+                None,
+                tls.as_ref(),
+            )?;
+            let client = redis::Client::open(url).map_err(|err| SessionError::config(err.to_string()))?;
+            Ok((client, safe_url))
+        })
+        .collect::<Result<Vec<_>, SessionError>>()?
+        .into_iter()
+        .unzip();
+
+    if sentinels.is_empty() {
+        return Err(SessionError::config(
+            "no sentinel config provided for sentinel redis store",
+        ));
+    }
+
+    info!("connecting to redis session stores monitored as {master_name} by sentinels at:");
+    for safe_url in safe_urls {
+        info!("- {safe_url}");
+    }
+
+    let client = SentinelClient {
+        master_name,
+        sentinels,
+        username: username.as_ref().map(ToString::to_string),
+        password: password.as_ref().map(ToString::to_string),
+        db,
+        tls: tls.clone(),
+    };
+    let connector = RedisConnector::new(tls.as_ref())?;
+
+    let pool = pool(Manager { client, connector }, pool_config)?;
+
+    // confirm a connection can be made
+    pool.get().await.map_err(SessionError::backend)?;
+
+    Ok(RedisStore {
+        key_name,
+        keyring: Arc::new(key.into()),
+        namespace: namespace.unwrap_or_default(),
+        codec,
+        compression,
+        command_timeout,
+        cluster: false,
         _value: PhantomData,
         pool,
     })
@@ -334,11 +1615,11 @@ pub async fn redis_store<T, KN, K, U, P, H>(
     config: RedisStoreConfig<KN, K, U, P>,
     node_configs: impl IntoIterator<Item = RedisStoreNodeConfig<H>>,
     is_cluster: bool,
-) -> Result<DynSessionStore<T>, Error>
+) -> Result<DynSessionStore<T>, SessionError>
 where
     T: 'static + Clone + DeserializeOwned + Serialize + Send + Sync,
     KN: ToString,
-    K: ToString,
+    K: Into<Keyring>,
     U: ToString,
     P: ToString,
     H: ToString,
@@ -349,9 +1630,9 @@ where
         let mut node_config_iter = node_configs.into_iter();
         let node_config = node_config_iter
             .next()
-            .ok_or_else(|| Error::msg("no node config provided for standalone redis store"))?;
+            .ok_or_else(|| SessionError::config("no node config provided for standalone redis store"))?;
         if node_config_iter.next().is_some() {
-            return Err(Error::msg(
+            return Err(SessionError::config(
                 "more than one node config provided for standalone redis store",
             ));
         }
@@ -365,24 +1646,31 @@ fn url(
     host: &str,
     port: Option<u16>,
     path: Option<&str>,
-    query: Option<&str>,
-) -> Result<Url, Error> {
-    let mut url = Url::parse(&format!("redis://{host}"))?;
+    tls: Option<&RedisStoreTlsConfig>,
+) -> Result<Url, SessionError> {
+    let scheme = match tls {
+        Some(_) => "rediss",
+        None => "redis",
+    };
+    let mut url = Url::parse(&format!("{scheme}://{host}")).map_err(|err| SessionError::config(err.to_string()))?;
 
     if let Some(username) = username {
         url.set_username(username)
-            .map_err(|_| Error::msg("could not set url username"))?;
+            .map_err(|_| SessionError::config("could not set url username"))?;
     }
 
     url.set_password(password)
-        .map_err(|_| Error::msg("could not set url password"))?;
+        .map_err(|_| SessionError::config("could not set url password"))?;
 
-    url.set_port(port).map_err(|_| Error::msg("could not set url port"))?;
+    url.set_port(port)
+        .map_err(|_| SessionError::config("could not set url port"))?;
 
     if let Some(path) = path {
         url.set_path(path);
     }
-    url.set_query(query);
+    if tls.is_some_and(|tls| tls.insecure) {
+        url.set_fragment(Some("insecure"));
+    }
 
     Ok(url)
 }
@@ -393,12 +1681,449 @@ fn safe_url(
     host: &str,
     port: Option<u16>,
     path: Option<&str>,
-    query: Option<&str>,
-) -> Result<String, Error> {
+    tls: Option<&RedisStoreTlsConfig>,
+) -> Result<String, SessionError> {
     let username = match username.is_some() || password.is_some() {
         true => Some("<credentials>"),
         false => None,
     };
-    let safe_url = format!("{}", url(username, None, host, port, path, query)?);
+    let safe_url = format!("{}", url(username, None, host, port, path, tls)?);
     Ok(safe_url.replace("%3C", "<").replace("%3E", ">"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::std::collections::BTreeSet;
+    use ::std::sync::atomic::{AtomicUsize, Ordering};
+
+    const NAMESPACES: [&str; 4] = ["", "sess:", "sess:{app}:", "sess:{}:"];
+
+    fn store(namespace: &str, cluster: bool) -> RedisStore<String, ()> {
+        RedisStore {
+            key_name: "sid".into(),
+            keyring: Arc::new("secret".into()),
+            namespace: namespace.into(),
+            codec: Codec::Json,
+            compression: None,
+            command_timeout: None,
+            cluster,
+            pool: (),
+            _value: PhantomData,
+        }
+    }
+
+    /// keys of a command, every argument of DEL and the first argument of the other commands
+    fn cmd_keys(cmd: &Cmd) -> Vec<String> {
+        let args: Vec<String> = cmd
+            .args_iter()
+            .filter_map(|arg| match arg {
+                redis::Arg::Simple(arg) => Some(String::from_utf8_lossy(arg).into_owned()),
+                redis::Arg::Cursor => None,
+            })
+            .collect();
+        match args[0].as_str() {
+            "DEL" => args[1..].to_vec(),
+            _ => args[1..2].to_vec(),
+        }
+    }
+
+    fn assert_single_slot(cmds: &[Cmd]) {
+        for cmd in cmds {
+            let keys = cmd_keys(cmd);
+            let slots: BTreeSet<u16> = keys.iter().map(|key| key_slot(key)).collect();
+            assert_eq!(slots.len(), 1, "{keys:?}");
+        }
+    }
+
+    #[test]
+    fn session_prefix_keys_share_the_session_key_slot() {
+        for namespace in ["", "sess:", "sess:{app}:"] {
+            let store = store(namespace, true);
+            let session_id = Uuid::new_v4();
+            assert_eq!(
+                key_slot(&store.session_key(&session_id)),
+                key_slot(&store.session_prefix_key(&session_id)),
+                "{namespace}"
+            );
+        }
+    }
+
+    #[test]
+    fn cluster_commands_only_access_a_single_slot() {
+        for namespace in NAMESPACES {
+            let store = store(namespace, true);
+            let session_ids: Vec<Uuid> = (0..16).map(|_| Uuid::new_v4()).collect();
+
+            assert_single_slot(&store.delete_session_cmds(&session_ids[0], Some("user")));
+            assert_single_slot(&store.rotate_session_cmds(&session_ids[0], &session_ids[1], b"{}", 1000, Some("user")));
+            assert_single_slot(&store.delete_prefix_cmds("user", &session_ids));
+        }
+    }
+
+    #[test]
+    fn session_keys_are_deleted_with_a_single_command() {
+        let session_id = Uuid::new_v4();
+        for cluster in [false, true] {
+            let cmds = store("sess:", cluster).delete_session_cmds(&session_id, None);
+            assert_eq!(cmds.len(), 1);
+            assert_eq!(cmd_keys(&cmds[0]).len(), 2);
+        }
+
+        // every key is deleted at once when keys do not need to be split by slot
+        let session_ids: Vec<Uuid> = (0..16).map(|_| Uuid::new_v4()).collect();
+        let cmds = store("sess:", false).delete_prefix_cmds("user", &session_ids);
+        assert_eq!(cmds.len(), 1);
+        assert_eq!(cmd_keys(&cmds[0]).len(), session_ids.len() * 2 + 1);
+    }
+
+    #[test]
+    fn key_slots_match_redis() {
+        // slots reported by `CLUSTER KEYSLOT`
+        assert_eq!(key_slot("foo"), 12182);
+        assert_eq!(key_slot("somekey"), 11058);
+        assert_eq!(key_slot("{}foo"), 9500);
+    }
+
+    #[test]
+    fn hash_tags_place_keys_in_the_same_slot() {
+        assert_eq!(key_slot("{user1000}.following"), 3443);
+        assert_eq!(key_slot("{user1000}.following"), key_slot("user1000"));
+        assert_eq!(key_slot("{user1000}.followers"), key_slot("user1000"));
+        // only the first hash tag is used
+        assert_eq!(key_slot("foo{bar}{zap}"), key_slot("bar"));
+        assert_eq!(key_slot("foo{{bar}}zap"), key_slot("{bar"));
+    }
+
+    #[test]
+    fn empty_hash_tags_hash_the_whole_key() {
+        assert_eq!(key_slot("foo{}{bar}"), 8363);
+        assert_ne!(key_slot("foo{}{bar}"), key_slot("bar"));
+        assert_ne!(key_slot("foo{bar"), key_slot("bar"));
+    }
+
+    #[test]
+    fn cluster_nodes_are_connected_with_their_clients_connector() {
+        let tls = RedisStoreTlsConfig::builder().client_cert(Vec::new()).build();
+        let nodes = vec![Url::parse("redis://user:pw@localhost:6379").unwrap()];
+        let client = ClusterClient::open(nodes, &RedisConnector::default(), Some(&tls)).unwrap();
+        let connector_id = client.connector_id.unwrap();
+
+        let node = |username: &str| {
+            redis::IntoConnectionInfo::into_connection_info(format!("redis://{username}:pw@10.0.0.1:6379")).unwrap()
+        };
+        let mut info = node(&format!("{CLUSTER_CONNECTOR_TAG}{connector_id}.user"));
+        untag_cluster_node(&mut info).unwrap();
+        assert_eq!(info.redis.username.as_deref(), Some("user"));
+        let mut info = node(&format!("{CLUSTER_CONNECTOR_TAG}{connector_id}."));
+        untag_cluster_node(&mut info).unwrap();
+        assert_eq!(info.redis.username, None);
+        // nodes of clients without a connector of their own keep their username
+        let mut info = node("user");
+        untag_cluster_node(&mut info).unwrap();
+        assert_eq!(info.redis.username.as_deref(), Some("user"));
+
+        drop(client);
+        let mut info = node(&format!("{CLUSTER_CONNECTOR_TAG}{connector_id}.user"));
+        assert!(untag_cluster_node(&mut info).is_err());
+    }
+
+    /// connection which never replies to its first command and counts the commands it is sent
+    struct Stalled(Arc<AtomicUsize>);
+
+    impl ConnectionLike for Stalled {
+        fn req_packed_command<'a>(&'a mut self, _: &'a Cmd) -> redis::RedisFuture<'a, redis::Value> {
+            match self.0.fetch_add(1, Ordering::SeqCst) {
+                0 => futures::future::pending().boxed(),
+                _ => async { Ok(redis::Value::Okay) }.boxed(),
+            }
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            _: &'a redis::Pipeline,
+            _: usize,
+            _: usize,
+        ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+            unimplemented!()
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    struct StalledManager(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl managed::Manager for StalledManager {
+        type Type = Stalled;
+        type Error = RedisError;
+
+        async fn create(&self) -> Result<Self::Type, Self::Error> {
+            Ok(Stalled(self.0.clone()))
+        }
+
+        async fn recycle(&self, _: &mut Self::Type, _: &Metrics) -> managed::RecycleResult<Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn timed_out_connections_refuse_further_commands() {
+        let sent = Arc::new(AtomicUsize::new(0));
+        let pool = Pool::<StalledManager>::builder(StalledManager(sent.clone()))
+            .build()
+            .unwrap();
+        let mut conn = TimedConnection {
+            conn: Some(pool.get().await.unwrap()),
+            timeout: Some(StdDuration::from_millis(10)),
+            timed_out: false,
+        };
+
+        let result = cmd("GET").arg("key").query_async::<_, ()>(&mut conn).await;
+        assert!(result.unwrap_err().is_timeout());
+        let result = unwatch_on_err(&mut conn, false, Err::<(), _>(SessionError::Conflict("set"))).await;
+        assert!(matches!(result, Err(SessionError::Conflict(_))));
+        assert!(cmd("PING")
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .unwrap_err()
+            .is_timeout());
+        // neither the `UNWATCH` nor the `PING` reached the connection
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        drop(conn);
+        assert_eq!(pool.status().size, 0);
+    }
+
+    /// tests against the redis at `REDIS_URL` and the sentinels at `REDIS_SENTINEL_URL`, e.g.
+    /// `redis://localhost:26379?sentinel=mymaster`, each passes without running when its url is not set,
+    /// the sentinel tests fail the master over so `REDIS_URL` must not point to a server the sentinels monitor
+    mod integration {
+        use super::*;
+
+        /// store with a namespace of its own, so that tests do not share keys
+        async fn store(var: &str) -> Option<(DynSessionStore<String>, RedisStoreUrl)> {
+            let url: RedisStoreUrl = std::env::var(var).ok()?.parse().unwrap();
+            let mut config = url.store_config("sid", "secret");
+            config.namespace = Some(format!("test:{}:", Uuid::new_v4()));
+            Some((redis_store_with_url(config, &url).await.unwrap(), url))
+        }
+
+        fn session(value: &str, max_age: Option<Duration>) -> Session<String> {
+            Session {
+                session_id: Uuid::nil(),
+                created_at: chrono::Utc::now().naive_utc(),
+                value: value.to_string(),
+                max_age,
+                expires: None,
+                idle_timeout: None,
+                max_lifetime: None,
+            }
+        }
+
+        async fn sleep(millis: u64) {
+            tokio::time::sleep(StdDuration::from_millis(millis)).await;
+        }
+
+        #[tokio::test]
+        async fn missing_sessions_are_not_found() {
+            let Some((store, _)) = store("REDIS_URL").await else {
+                return;
+            };
+            let session_id = Uuid::new_v4();
+            store.set(None, &session_id, &session("value", None)).await.unwrap();
+            assert_eq!(store.get(&session_id).await.unwrap().value, "value");
+            store.delete(&session_id).await.unwrap();
+
+            assert!(matches!(store.get(&session_id).await, Err(SessionError::NotFound)));
+            assert!(matches!(
+                store.touch(&session_id, Duration::hours(1)).await,
+                Err(SessionError::NotFound)
+            ));
+            assert!(matches!(
+                store.update(&session_id, &"value".into()).await,
+                Err(SessionError::NotFound)
+            ));
+            assert!(matches!(
+                store.modify(&session_id, &mut |_| {}).await,
+                Err(SessionError::NotFound)
+            ));
+            assert!(matches!(
+                store.rotate(&session_id, &Uuid::new_v4()).await,
+                Err(SessionError::NotFound)
+            ));
+        }
+
+        #[tokio::test]
+        async fn sub_second_ttls_expire_sessions() {
+            let Some((store, _)) = store("REDIS_URL").await else {
+                return;
+            };
+            let session_id = Uuid::new_v4();
+            let expiring = session("value", Some(Duration::milliseconds(300)));
+            store.set(Some("user".into()), &session_id, &expiring).await.unwrap();
+            assert!(store.get(&session_id).await.is_ok());
+            sleep(500).await;
+            assert!(matches!(store.get(&session_id).await, Err(SessionError::NotFound)));
+
+            store.set(None, &session_id, &session("value", None)).await.unwrap();
+            store.touch(&session_id, Duration::milliseconds(300)).await.unwrap();
+            assert!(store.get(&session_id).await.is_ok());
+            sleep(500).await;
+            assert!(matches!(store.get(&session_id).await, Err(SessionError::NotFound)));
+        }
+
+        #[tokio::test]
+        async fn updates_keep_the_ttl_and_do_not_recreate_expired_sessions() {
+            let Some((store, _)) = store("REDIS_URL").await else {
+                return;
+            };
+            let session_id = Uuid::new_v4();
+            let expiring = session("value", Some(Duration::milliseconds(500)));
+            store.set(None, &session_id, &expiring).await.unwrap();
+
+            store.update(&session_id, &"updated".into()).await.unwrap();
+            assert_eq!(store.get(&session_id).await.unwrap().value, "updated");
+            sleep(700).await;
+            assert!(matches!(store.get(&session_id).await, Err(SessionError::NotFound)));
+            assert!(matches!(
+                store.update(&session_id, &"recreated".into()).await,
+                Err(SessionError::NotFound)
+            ));
+            assert!(matches!(store.get(&session_id).await, Err(SessionError::NotFound)));
+        }
+
+        #[tokio::test]
+        async fn rotate_moves_the_session_its_prefix_and_its_ttl() {
+            let Some((store, _)) = store("REDIS_URL").await else {
+                return;
+            };
+            let session_id = Uuid::new_v4();
+            let new_session_id = Uuid::new_v4();
+            let expiring = session("value", Some(Duration::milliseconds(500)));
+            store.set(Some("user".into()), &session_id, &expiring).await.unwrap();
+
+            let session = store.rotate(&session_id, &new_session_id).await.unwrap();
+            assert_eq!(session.session_id, new_session_id);
+            assert!(matches!(store.get(&session_id).await, Err(SessionError::NotFound)));
+            assert_eq!(store.get(&new_session_id).await.unwrap().value, "value");
+            assert_eq!(store.list_by_prefix("user").await.unwrap(), vec![new_session_id]);
+
+            sleep(700).await;
+            assert!(matches!(store.get(&new_session_id).await, Err(SessionError::NotFound)));
+            assert!(store.list_by_prefix("user").await.unwrap().is_empty());
+        }
+
+        #[tokio::test]
+        async fn prefix_sets_follow_their_sessions() {
+            let Some((store, _)) = store("REDIS_URL").await else {
+                return;
+            };
+            let session_ids = [Uuid::new_v4(), Uuid::new_v4()];
+            for session_id in &session_ids {
+                store
+                    .set(Some("user".into()), session_id, &session("value", None))
+                    .await
+                    .unwrap();
+            }
+
+            // storing a session with another prefix moves it out of its previous prefix set
+            store
+                .set(Some("other".into()), &session_ids[0], &session("value", None))
+                .await
+                .unwrap();
+            assert_eq!(store.list_by_prefix("user").await.unwrap(), vec![session_ids[1]]);
+            assert_eq!(store.list_by_prefix("other").await.unwrap(), vec![session_ids[0]]);
+
+            store.delete(&session_ids[0]).await.unwrap();
+            assert!(store.list_by_prefix("other").await.unwrap().is_empty());
+
+            store.delete_by_prefix("user").await.unwrap();
+            assert!(store.list_by_prefix("user").await.unwrap().is_empty());
+            assert!(matches!(store.get(&session_ids[1]).await, Err(SessionError::NotFound)));
+        }
+
+        #[tokio::test]
+        async fn concurrent_modifications_are_all_applied() {
+            let Some((store, url)) = store("REDIS_URL").await else {
+                return;
+            };
+            // cluster stores cannot detect conflicting modifications
+            if url.mode == RedisStoreMode::Cluster {
+                return;
+            }
+            let session_id = Uuid::new_v4();
+            store.set(None, &session_id, &session("", None)).await.unwrap();
+
+            // every attempt which conflicts is preceded by another modification being stored, so as many
+            // concurrent modifications as there are attempts always succeed
+            let modifications = (0..MAX_TRANSACTION_ATTEMPTS).map(|_| async {
+                store.modify(&session_id, &mut |value| value.push('.')).await.unwrap();
+            });
+            futures::future::join_all(modifications).await;
+            assert_eq!(
+                store.get(&session_id).await.unwrap().value.len(),
+                MAX_TRANSACTION_ATTEMPTS
+            );
+        }
+
+        /// fails the monitored master over to one of its replicas, which requires the master to have a replica
+        #[tokio::test]
+        async fn sentinel_stores_follow_a_failover() {
+            let Some((store, url)) = store("REDIS_SENTINEL_URL").await else {
+                return;
+            };
+            let RedisStoreMode::Sentinel { master_name } = &url.mode else {
+                panic!("REDIS_SENTINEL_URL must select a master with `?sentinel=<master name>`")
+            };
+            let session_id = Uuid::new_v4();
+            store.set(None, &session_id, &session("value", None)).await.unwrap();
+            assert_eq!(store.get(&session_id).await.unwrap().value, "value");
+
+            let sentinel = &url.nodes[0];
+            let sentinel = redis::Client::open(format!("redis://{}:{}", sentinel.host, sentinel.port.unwrap_or(26379)));
+            let sentinel = sentinel.unwrap().get_async_connection().await.unwrap();
+            let master_addr = |mut sentinel| async move {
+                let addr: (String, u16) = cmd("SENTINEL")
+                    .arg("get-master-addr-by-name")
+                    .arg(master_name)
+                    .query_async(&mut sentinel)
+                    .await
+                    .unwrap();
+                (sentinel, addr)
+            };
+            let (mut sentinel, (host, port)) = master_addr(sentinel).await;
+            cmd("SENTINEL")
+                .arg("FAILOVER")
+                .arg(master_name)
+                .query_async::<_, ()>(&mut sentinel)
+                .await
+                .unwrap();
+
+            // the pooled connection to the previous master is only dropped once the master reports the replica role
+            let mut previous_master = redis::Client::open(format!("redis://{host}:{port}"))
+                .unwrap()
+                .get_async_connection()
+                .await
+                .unwrap();
+            for _ in 0..300 {
+                let (next_sentinel, addr) = master_addr(sentinel).await;
+                sentinel = next_sentinel;
+                if addr != (host.clone(), port) && !is_master(&mut previous_master).await.unwrap() {
+                    break;
+                }
+                sleep(100).await;
+            }
+            assert!(!is_master(&mut previous_master).await.unwrap());
+
+            let session_id = Uuid::new_v4();
+            store
+                .set(None, &session_id, &session("failed over", None))
+                .await
+                .unwrap();
+            assert_eq!(store.get(&session_id).await.unwrap().value, "failed over");
+        }
+    }
+}
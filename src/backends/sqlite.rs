@@ -0,0 +1,629 @@
+use crate::*;
+use ::chrono::Utc;
+use ::deadpool_sqlite::{Config, Pool, Runtime};
+use ::derivative::Derivative;
+use ::futures::lock::Mutex;
+use ::log::{error, info};
+use ::rusqlite::{params, Connection, OptionalExtension};
+use ::serde::{de::DeserializeOwned, Serialize};
+use ::std::collections::HashMap;
+use ::std::path::PathBuf;
+use ::std::sync::{Arc, Weak};
+use ::std::{marker::PhantomData, ops::Deref, time::Duration};
+use ::typed_builder::TypedBuilder;
+use ::uuid::Uuid;
+
+const DEFAULT_PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+const MIGRATIONS: &str = "
+    PRAGMA journal_mode = WAL;
+
+    CREATE TABLE IF NOT EXISTS sessions (
+        id TEXT PRIMARY KEY NOT NULL,
+        body TEXT NOT NULL,
+        expires_at INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS sessions_expires_at ON sessions (expires_at);
+
+    CREATE TABLE IF NOT EXISTS session_prefixes (
+        prefix TEXT NOT NULL,
+        session_id TEXT NOT NULL,
+        PRIMARY KEY (prefix, session_id)
+    );
+    CREATE INDEX IF NOT EXISTS session_prefixes_session_id ON session_prefixes (session_id);
+";
+
+#[derive(Clone, Copy, Derivative, TypedBuilder)]
+#[derivative(Debug)]
+pub struct SqliteStoreConfig<KN, K, PA> {
+    pub key_name: KN,
+    #[derivative(Debug = "ignore")]
+    pub key: K,
+    /// path to the sqlite database file, created if it does not exist
+    pub path: PA,
+    /// how often expired sessions are purged in the background, defaults to one minute
+    #[builder(default, setter(strip_option))]
+    pub purge_interval: Option<Duration>,
+}
+
+/// session store which persists sessions in an embedded sqlite database
+///
+/// sessions are kept in a `sessions` table and the prefixes passed to `set` are kept in a
+/// `session_prefixes` index table, expired sessions are never returned from `get` and are
+/// periodically purged
+#[derive(Clone, Derivative)]
+#[derivative(Debug)]
+pub struct SqliteStore<T> {
+    key_name: String,
+    keyring: Arc<Keyring>,
+    #[derivative(Debug = "ignore")]
+    pool: Arc<Pool>,
+    /// locks of the sessions being modified by this process, which serialize the modifications of each session
+    /// as they would otherwise keep conflicting
+    #[derivative(Debug = "ignore")]
+    modify_locks: Arc<std::sync::Mutex<HashMap<Uuid, Weak<Mutex<()>>>>>,
+    #[derivative(Debug = "ignore")]
+    _value: PhantomData<T>,
+}
+
+/// number of times a modification is retried when the session is modified concurrently before it is stored
+const MAX_MODIFY_ATTEMPTS: usize = 8;
+
+impl<T> SqliteStore<T> {
+    /// reads the serialized body of an unexpired session
+    async fn body(&self, session_id: &Uuid) -> Result<String, SessionError> {
+        let id = format!("{session_id}");
+        let now = Utc::now().timestamp();
+
+        self.interact(move |conn| {
+            let body: Option<String> = conn
+                .query_row(
+                    "SELECT body FROM sessions WHERE id = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+                    params![id, now],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            Ok(body)
+        })
+        .await?
+        .ok_or_else(|| SessionError::NotFound)
+    }
+
+    /// lock held while a session is modified, shared by the concurrent modifications of the session
+    fn modify_lock(&self, session_id: &Uuid) -> Arc<Mutex<()>> {
+        let mut modify_locks = self.modify_locks.lock().unwrap();
+        if let Some(modify_lock) = modify_locks.get(session_id).and_then(Weak::upgrade) {
+            return modify_lock;
+        }
+        modify_locks.retain(|_, modify_lock| modify_lock.strong_count() > 0);
+        let modify_lock = Arc::default();
+        modify_locks.insert(*session_id, Arc::downgrade(&modify_lock));
+        modify_lock
+    }
+
+    async fn interact<R: 'static + Send>(
+        &self,
+        f: impl 'static + FnOnce(&mut Connection) -> Result<R, SessionError> + Send,
+    ) -> Result<R, SessionError> {
+        let conn = self.pool.get().await.map_err(SessionError::backend)?;
+        conn.interact(f)
+            .await
+            .map_err(|err| SessionError::backend(err.to_string()))?
+    }
+}
+
+#[async_trait]
+impl<T> SessionStore for SqliteStore<T>
+where
+    T: 'static + Clone + DeserializeOwned + Serialize + Send + Sync,
+{
+    type Value = T;
+
+    fn key_name(&self) -> &str {
+        &self.key_name
+    }
+    fn keyring(&self) -> &Keyring {
+        self.keyring.deref()
+    }
+
+    async fn set(
+        &self,
+        prefix: Option<String>,
+        session_id: &Uuid,
+        session: &Session<Self::Value>,
+    ) -> Result<(), SessionError> {
+        let body = serde_json::to_string(&session).map_err(SessionError::codec)?;
+        let expires_at = session.ttl().map(|ttl| (Utc::now() + ttl).timestamp());
+        let session_id = format!("{session_id}");
+
+        self.interact(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO sessions (id, body, expires_at) VALUES (?1, ?2, ?3)
+                ON CONFLICT (id) DO UPDATE SET body = excluded.body, expires_at = excluded.expires_at",
+                params![session_id, body, expires_at],
+            )?;
+            tx.execute(
+                "DELETE FROM session_prefixes WHERE session_id = ?1",
+                params![session_id],
+            )?;
+            if let Some(prefix) = prefix {
+                tx.execute(
+                    "INSERT INTO session_prefixes (prefix, session_id) VALUES (?1, ?2)",
+                    params![prefix, session_id],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get(&self, session_id: &Uuid) -> Result<Session<Self::Value>, SessionError> {
+        let body = self.body(session_id).await?;
+        let mut session: Session<Self::Value> = serde_json::from_str(&body).map_err(SessionError::codec)?;
+        session.session_id = *session_id;
+        session.unexpired()
+    }
+
+    async fn delete(&self, session_id: &Uuid) -> Result<(), SessionError> {
+        let session_id = format!("{session_id}");
+        self.interact(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM sessions WHERE id = ?1", params![session_id])?;
+            tx.execute(
+                "DELETE FROM session_prefixes WHERE session_id = ?1",
+                params![session_id],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update(&self, session_id: &Uuid, value: &Self::Value) -> Result<(), SessionError> {
+        self.modify(session_id, &mut |session_value| *session_value = value.clone())
+            .await?;
+        Ok(())
+    }
+
+    /// modifies the session and replaces its body only if the body has not changed since it was read, retrying
+    /// the modification otherwise, as sqlite cannot lock a single row for the duration of `modify_fn`
+    async fn modify(
+        &self,
+        session_id: &Uuid,
+        modify_fn: &mut (dyn for<'v> FnMut(&'v mut Self::Value) + Send),
+    ) -> Result<Session<Self::Value>, SessionError> {
+        let modify_lock = self.modify_lock(session_id);
+        let _modify_guard = modify_lock.lock().await;
+        for _ in 0..MAX_MODIFY_ATTEMPTS {
+            let body = self.body(session_id).await?;
+            let mut session: Session<Self::Value> = serde_json::from_str(&body).map_err(SessionError::codec)?;
+            session.session_id = *session_id;
+            let mut session = session.unexpired()?;
+            modify_fn(&mut session.value);
+            let modified = serde_json::to_string(&session).map_err(SessionError::codec)?;
+
+            let id = format!("{session_id}");
+            let replaced = self
+                .interact(move |conn| {
+                    let replaced = conn.execute(
+                        "UPDATE sessions SET body = ?3 WHERE id = ?1 AND body = ?2",
+                        params![id, body, modified],
+                    )?;
+                    Ok(replaced)
+                })
+                .await?;
+            if replaced > 0 {
+                return Ok(session);
+            }
+        }
+
+        Err(SessionError::Conflict("modify session"))
+    }
+
+    async fn rotate(&self, session_id: &Uuid, new_session_id: &Uuid) -> Result<Session<Self::Value>, SessionError> {
+        let id = format!("{session_id}");
+        let new_id = format!("{new_session_id}");
+        let now = Utc::now().timestamp();
+
+        let body = self
+            .interact(move |conn| {
+                let tx = conn.transaction()?;
+                let rotated = tx.execute(
+                    "UPDATE sessions SET id = ?2 WHERE id = ?1 AND (expires_at IS NULL OR expires_at > ?3)",
+                    params![id, new_id, now],
+                )?;
+                if rotated == 0 {
+                    return Ok(None);
+                }
+                tx.execute(
+                    "UPDATE session_prefixes SET session_id = ?2 WHERE session_id = ?1",
+                    params![id, new_id],
+                )?;
+                let body: String = tx.query_row("SELECT body FROM sessions WHERE id = ?1", params![new_id], |row| {
+                    row.get(0)
+                })?;
+                tx.commit()?;
+                Ok(Some(body))
+            })
+            .await?
+            .ok_or_else(|| SessionError::NotFound)?;
+
+        let mut session: Session<Self::Value> = serde_json::from_str(&body).map_err(SessionError::codec)?;
+        session.session_id = *new_session_id;
+        session.unexpired()
+    }
+
+    async fn touch(&self, session_id: &Uuid, extend_by: chrono::Duration) -> Result<(), SessionError> {
+        let session_id = format!("{session_id}");
+        let now = Utc::now();
+        let expires_at = (now + extend_by).timestamp();
+        let now = now.timestamp();
+
+        let touched = self
+            .interact(move |conn| {
+                let touched = conn.execute(
+                    "UPDATE sessions SET expires_at = ?2 WHERE id = ?1 AND (expires_at IS NULL OR expires_at > ?3)",
+                    params![session_id, expires_at, now],
+                )?;
+                Ok(touched)
+            })
+            .await?;
+
+        match touched {
+            0 => Err(SessionError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list_by_prefix(&self, prefix: &str) -> Result<Vec<Uuid>, SessionError> {
+        let prefix = prefix.to_string();
+        let now = Utc::now().timestamp();
+
+        let session_ids = self
+            .interact(move |conn| {
+                let mut statement = conn.prepare_cached(
+                    "SELECT session_prefixes.session_id FROM session_prefixes
+                    JOIN sessions ON sessions.id = session_prefixes.session_id
+                    WHERE session_prefixes.prefix = ?1 AND (sessions.expires_at IS NULL OR sessions.expires_at > ?2)",
+                )?;
+                let session_ids = statement
+                    .query_map(params![prefix, now], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(session_ids)
+            })
+            .await?;
+
+        session_ids
+            .iter()
+            .map(|session_id| Uuid::parse_str(session_id).map_err(SessionError::codec))
+            .collect()
+    }
+
+    async fn delete_by_prefix(&self, prefix: &str) -> Result<(), SessionError> {
+        let prefix = prefix.to_string();
+        self.interact(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM sessions WHERE id IN (SELECT session_id FROM session_prefixes WHERE prefix = ?1)",
+                params![prefix],
+            )?;
+            tx.execute("DELETE FROM session_prefixes WHERE prefix = ?1", params![prefix])?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+pub async fn sqlite_store<T, KN, K, PA>(
+    SqliteStoreConfig {
+        key_name,
+        key,
+        path,
+        purge_interval,
+    }: SqliteStoreConfig<KN, K, PA>,
+) -> Result<SqliteStore<T>, SessionError>
+where
+    T: 'static + Send + Sync,
+    KN: ToString,
+    K: Into<Keyring>,
+    PA: Into<PathBuf>,
+{
+    let key_name = key_name.to_string();
+    let path = path.into();
+    let purge_interval = purge_interval.unwrap_or(DEFAULT_PURGE_INTERVAL);
+    if purge_interval.is_zero() {
+        return Err(SessionError::config(
+            "sqlite session store purge interval must be non-zero",
+        ));
+    }
+
+    info!("opening sqlite session store at {}", path.display());
+
+    let pool = Config::new(path)
+        .create_pool(Runtime::Tokio1)
+        .map_err(SessionError::backend)?;
+
+    let store = SqliteStore {
+        key_name,
+        keyring: Arc::new(key.into()),
+        pool: Arc::new(pool),
+        modify_locks: Arc::default(),
+        _value: PhantomData,
+    };
+
+    // confirm a connection can be made and that the session tables exist
+    store
+        .interact(|conn| {
+            conn.execute_batch(MIGRATIONS)?;
+            Ok(())
+        })
+        .await?;
+
+    tokio::spawn(purge_periodically(
+        Arc::downgrade(&store.pool),
+        purge_interval,
+        |pool: Arc<Pool>| async move {
+            let result = match pool.get().await {
+                Ok(conn) => match conn.interact(purge_expired).await {
+                    Ok(result) => result.map_err(SessionError::from),
+                    Err(err) => Err(SessionError::backend(err.to_string())),
+                },
+                Err(err) => Err(SessionError::backend(err)),
+            };
+            if let Err(err) = result {
+                error!("unable to purge expired sessions from sqlite session store: {err}");
+            }
+        },
+    ));
+
+    Ok(store)
+}
+
+/// deletes expired sessions and their prefix index entries
+fn purge_expired(conn: &mut Connection) -> Result<usize, rusqlite::Error> {
+    let now = Utc::now().timestamp();
+    let tx = conn.transaction()?;
+    tx.execute(
+        "DELETE FROM session_prefixes WHERE session_id IN (SELECT id FROM sessions WHERE expires_at <= ?1)",
+        params![now],
+    )?;
+    let purged = tx.execute("DELETE FROM sessions WHERE expires_at <= ?1", params![now])?;
+    tx.commit()?;
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::chrono::Duration;
+    use ::tempfile::TempDir;
+
+    /// store backed by a database in a temporary directory, which is removed when the directory is dropped
+    async fn store() -> (TempDir, SqliteStore<String>) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = sqlite_store(
+            SqliteStoreConfig::builder()
+                .key_name("sid")
+                .key("secret")
+                .path(dir.path().join("sessions.db"))
+                .build(),
+        )
+        .await
+        .unwrap();
+        (dir, store)
+    }
+
+    fn session(value: &str) -> Session<String> {
+        Session {
+            session_id: Uuid::nil(),
+            created_at: Utc::now().naive_utc(),
+            value: value.to_string(),
+            max_age: None,
+            expires: None,
+            idle_timeout: None,
+            max_lifetime: None,
+        }
+    }
+
+    /// sessions are expired at second granularity
+    async fn sleep_past_expiry() {
+        tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+    }
+
+    #[tokio::test]
+    async fn get_returns_stored_sessions() {
+        let (_dir, store) = store().await;
+        let session_id = Uuid::new_v4();
+        store.set(None, &session_id, &session("value")).await.unwrap();
+
+        let session = store.get(&session_id).await.unwrap();
+        assert_eq!(session.session_id, session_id);
+        assert_eq!(session.value, "value");
+        assert!(matches!(store.get(&Uuid::new_v4()).await, Err(SessionError::NotFound)));
+
+        store.delete(&session_id).await.unwrap();
+        assert!(matches!(store.get(&session_id).await, Err(SessionError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_not_found_after_their_ttl() {
+        let (_dir, store) = store().await;
+        let session_id = Uuid::new_v4();
+        let session = Session {
+            max_age: Some(Duration::seconds(1)),
+            ..session("value")
+        };
+        store.set(Some("user".into()), &session_id, &session).await.unwrap();
+        assert!(store.get(&session_id).await.is_ok());
+
+        sleep_past_expiry().await;
+        assert!(matches!(store.get(&session_id).await, Err(SessionError::NotFound)));
+        assert!(matches!(
+            store.touch(&session_id, Duration::hours(1)).await,
+            Err(SessionError::NotFound)
+        ));
+        assert!(matches!(
+            store.rotate(&session_id, &Uuid::new_v4()).await,
+            Err(SessionError::NotFound)
+        ));
+        assert!(store.list_by_prefix("user").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn purge_deletes_expired_sessions_and_their_prefixes() {
+        let (_dir, store) = store().await;
+        let expired_session_id = Uuid::new_v4();
+        let session_id = Uuid::new_v4();
+        let expiring = Session {
+            max_age: Some(Duration::seconds(1)),
+            ..session("expiring")
+        };
+        store
+            .set(Some("user".into()), &expired_session_id, &expiring)
+            .await
+            .unwrap();
+        store
+            .set(Some("user".into()), &session_id, &session("value"))
+            .await
+            .unwrap();
+
+        sleep_past_expiry().await;
+        let purged = store
+            .interact(|conn| purge_expired(conn).map_err(SessionError::from))
+            .await
+            .unwrap();
+        assert_eq!(purged, 1);
+
+        let rows = store
+            .interact(|conn| {
+                let sessions: usize = conn.query_row("SELECT COUNT(*) FROM sessions", [], |row| row.get(0))?;
+                let prefixes: usize = conn.query_row("SELECT COUNT(*) FROM session_prefixes", [], |row| row.get(0))?;
+                Ok((sessions, prefixes))
+            })
+            .await
+            .unwrap();
+        assert_eq!(rows, (1, 1));
+        assert_eq!(store.list_by_prefix("user").await.unwrap(), vec![session_id]);
+    }
+
+    #[tokio::test]
+    async fn touch_extends_the_ttl() {
+        let (_dir, store) = store().await;
+        let session_id = Uuid::new_v4();
+        let session = Session {
+            max_age: Some(Duration::seconds(1)),
+            ..session("value")
+        };
+        store.set(None, &session_id, &session).await.unwrap();
+        store.touch(&session_id, Duration::hours(1)).await.unwrap();
+
+        sleep_past_expiry().await;
+        assert!(store.get(&session_id).await.is_ok());
+        assert!(matches!(
+            store.touch(&Uuid::new_v4(), Duration::hours(1)).await,
+            Err(SessionError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_listed_and_deleted_by_prefix() {
+        let (_dir, store) = store().await;
+        let user_session_ids = [Uuid::new_v4(), Uuid::new_v4()];
+        for session_id in &user_session_ids {
+            store
+                .set(Some("user".into()), session_id, &session("user"))
+                .await
+                .unwrap();
+        }
+        let other_session_id = Uuid::new_v4();
+        store
+            .set(Some("other".into()), &other_session_id, &session("other"))
+            .await
+            .unwrap();
+
+        let mut session_ids = store.list_by_prefix("user").await.unwrap();
+        session_ids.sort();
+        let mut expected = user_session_ids.to_vec();
+        expected.sort();
+        assert_eq!(session_ids, expected);
+        assert!(store.list_by_prefix("missing").await.unwrap().is_empty());
+
+        // storing a session with another prefix moves it out of its previous prefix
+        store
+            .set(Some("other".into()), &user_session_ids[0], &session("user"))
+            .await
+            .unwrap();
+        assert_eq!(store.list_by_prefix("user").await.unwrap(), vec![user_session_ids[1]]);
+
+        store.delete_by_prefix("user").await.unwrap();
+        assert!(store.list_by_prefix("user").await.unwrap().is_empty());
+        assert!(matches!(
+            store.get(&user_session_ids[1]).await,
+            Err(SessionError::NotFound)
+        ));
+        assert!(store.get(&user_session_ids[0]).await.is_ok());
+        assert!(store.get(&other_session_id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rotate_moves_the_session_and_its_prefix() {
+        let (_dir, store) = store().await;
+        let session_id = Uuid::new_v4();
+        let new_session_id = Uuid::new_v4();
+        store
+            .set(Some("user".into()), &session_id, &session("value"))
+            .await
+            .unwrap();
+
+        let session = store.rotate(&session_id, &new_session_id).await.unwrap();
+        assert_eq!(session.session_id, new_session_id);
+        assert_eq!(session.value, "value");
+        assert!(matches!(store.get(&session_id).await, Err(SessionError::NotFound)));
+        assert_eq!(store.get(&new_session_id).await.unwrap().value, "value");
+        assert_eq!(store.list_by_prefix("user").await.unwrap(), vec![new_session_id]);
+        assert!(matches!(
+            store.rotate(&session_id, &Uuid::new_v4()).await,
+            Err(SessionError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn modify_applies_the_closure_to_the_stored_value() {
+        let (_dir, store) = store().await;
+        let session_id = Uuid::new_v4();
+        store.set(None, &session_id, &session("value")).await.unwrap();
+
+        let session = store
+            .modify(&session_id, &mut |value| value.push_str("-modified"))
+            .await
+            .unwrap();
+        assert_eq!(session.value, "value-modified");
+        assert_eq!(store.get(&session_id).await.unwrap().value, "value-modified");
+        assert!(matches!(
+            store.modify(&Uuid::new_v4(), &mut |_| {}).await,
+            Err(SessionError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn concurrent_modifications_are_all_applied() {
+        let (_dir, store) = store().await;
+        let session_id = Uuid::new_v4();
+        store.set(None, &session_id, &session("")).await.unwrap();
+
+        let modifications = (0..16).map(|_| async {
+            store.modify(&session_id, &mut |value| value.push('.')).await.unwrap();
+        });
+        futures::future::join_all(modifications).await;
+        assert_eq!(store.get(&session_id).await.unwrap().value.len(), 16);
+        assert!(store
+            .modify_locks
+            .lock()
+            .unwrap()
+            .values()
+            .all(|lock| lock.strong_count() == 0));
+    }
+}
@@ -88,6 +88,8 @@ fn main() -> Result<(), Error> {
             },
             max_age: None,
             expires: None,
+            idle_timeout: None,
+            max_lifetime: None,
         },
         &jwt_public_certificate,
         &validation,
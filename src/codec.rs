@@ -0,0 +1,350 @@
+use crate::SessionError;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// every session serializes to a json object, so json values are identified by their opening brace
+/// and need no header byte of their own, keeping them readable by releases which predate codecs
+const JSON_HEADER: u8 = b'{';
+#[cfg(feature = "msgpack-codec")]
+const MSGPACK_HEADER: u8 = 0x01;
+#[cfg(feature = "cbor-codec")]
+const CBOR_HEADER: u8 = 0x02;
+#[cfg(feature = "bincode-codec")]
+const BINCODE_HEADER: u8 = 0x03;
+#[cfg(feature = "zstd-compression")]
+const ZSTD_HEADER: u8 = 0x10;
+#[cfg(feature = "lz4-compression")]
+const LZ4_HEADER: u8 = 0x11;
+
+// without a compression feature enabled `Compression` cannot be constructed
+#[cfg_attr(
+    not(any(feature = "zstd-compression", feature = "lz4-compression")),
+    allow(dead_code)
+)]
+const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// format sessions are serialized with by stores which keep them as bytes
+///
+/// encoded values start with a header byte identifying the codec which wrote them, so a store can switch
+/// codecs and still read the values written before the switch
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Codec {
+    #[default]
+    Json,
+    /// messagepack with struct fields encoded by name, enabled with the `msgpack-codec` feature
+    #[cfg(feature = "msgpack-codec")]
+    MessagePack,
+    /// enabled with the `cbor-codec` feature
+    #[cfg(feature = "cbor-codec")]
+    Cbor,
+    /// the most compact codec, enabled with the `bincode-codec` feature
+    ///
+    /// bincode is not self-describing, session values must not skip fields when serializing,
+    /// e.g. with `#[serde(skip_serializing_if = "...")]` or `#[skip_serializing_none]`
+    #[cfg(feature = "bincode-codec")]
+    Bincode,
+}
+
+impl Codec {
+    fn header(&self) -> u8 {
+        match self {
+            Self::Json => JSON_HEADER,
+            #[cfg(feature = "msgpack-codec")]
+            Self::MessagePack => MSGPACK_HEADER,
+            #[cfg(feature = "cbor-codec")]
+            Self::Cbor => CBOR_HEADER,
+            #[cfg(feature = "bincode-codec")]
+            Self::Bincode => BINCODE_HEADER,
+        }
+    }
+
+    fn from_header(header: u8) -> Result<Self, SessionError> {
+        match header {
+            JSON_HEADER => Ok(Self::Json),
+            #[cfg(feature = "msgpack-codec")]
+            MSGPACK_HEADER => Ok(Self::MessagePack),
+            #[cfg(feature = "cbor-codec")]
+            CBOR_HEADER => Ok(Self::Cbor),
+            #[cfg(feature = "bincode-codec")]
+            BINCODE_HEADER => Ok(Self::Bincode),
+            header => Err(SessionError::codec(format!(
+                "unknown session codec header {header:#04x}, the codec may not be enabled"
+            ))),
+        }
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, SessionError> {
+        let mut encoded = vec![];
+        // json values are identified by their own opening brace
+        if *self != Self::Json {
+            encoded.push(self.header());
+        }
+        match self {
+            Self::Json => serde_json::to_writer(&mut encoded, value).map_err(SessionError::codec)?,
+            #[cfg(feature = "msgpack-codec")]
+            Self::MessagePack => rmp_serde::encode::write_named(&mut encoded, value).map_err(SessionError::codec)?,
+            #[cfg(feature = "cbor-codec")]
+            Self::Cbor => ciborium::ser::into_writer(value, &mut encoded).map_err(SessionError::codec)?,
+            #[cfg(feature = "bincode-codec")]
+            Self::Bincode => bincode::serialize_into(&mut encoded, value).map_err(SessionError::codec)?,
+        }
+        Ok(encoded)
+    }
+
+    /// encodes the value and compresses it if it is larger than the compression threshold
+    pub fn encode_compressed<T: Serialize>(
+        &self,
+        value: &T,
+        compression: Option<&Compression>,
+    ) -> Result<Vec<u8>, SessionError> {
+        let encoded = self.encode(value)?;
+        match compression {
+            Some(compression) if encoded.len() >= compression.threshold => compression.compress(&encoded),
+            _ => Ok(encoded),
+        }
+    }
+
+    /// decodes a value written by any enabled codec, regardless of which codec is currently in use,
+    /// decompressing it first if it was compressed
+    pub fn decode<T: DeserializeOwned>(encoded: &[u8]) -> Result<T, SessionError> {
+        match Compression::decompress(encoded)? {
+            Some(decompressed) => Self::decode_uncompressed(&decompressed),
+            None => Self::decode_uncompressed(encoded),
+        }
+    }
+
+    fn decode_uncompressed<T: DeserializeOwned>(encoded: &[u8]) -> Result<T, SessionError> {
+        let Some(header) = encoded.first() else {
+            return Err(SessionError::codec("unable to decode empty session value"));
+        };
+        match Self::from_header(*header)? {
+            Self::Json => serde_json::from_slice(encoded).map_err(SessionError::codec),
+            #[cfg(feature = "msgpack-codec")]
+            Self::MessagePack => rmp_serde::from_slice(&encoded[1..]).map_err(SessionError::codec),
+            #[cfg(feature = "cbor-codec")]
+            Self::Cbor => ciborium::de::from_reader(&encoded[1..]).map_err(SessionError::codec),
+            #[cfg(feature = "bincode-codec")]
+            Self::Bincode => bincode::deserialize(&encoded[1..]).map_err(SessionError::codec),
+        }
+    }
+}
+
+/// compression applied to encoded sessions which are at least `threshold` bytes long
+///
+/// compressed values start with a header byte identifying the algorithm, so compressed and uncompressed
+/// values can be read side by side and the threshold or algorithm can be changed at any time
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Compression {
+    algorithm: CompressionAlgorithm,
+    threshold: usize,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CompressionAlgorithm {
+    #[cfg(feature = "zstd-compression")]
+    Zstd { level: i32 },
+    #[cfg(feature = "lz4-compression")]
+    Lz4,
+}
+
+impl Compression {
+    /// zstd compression at the provided level, `0` uses zstd's default level, enabled with the `zstd-compression` feature
+    #[cfg(feature = "zstd-compression")]
+    pub fn zstd(level: i32) -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::Zstd { level },
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+
+    /// lz4 compression, faster but less compact than zstd, enabled with the `lz4-compression` feature
+    #[cfg(feature = "lz4-compression")]
+    pub fn lz4() -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::Lz4,
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+
+    /// size in bytes from which encoded sessions are compressed, defaults to 1024
+    pub fn threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    #[cfg_attr(
+        not(any(feature = "zstd-compression", feature = "lz4-compression")),
+        allow(unused_variables)
+    )]
+    fn compress(&self, encoded: &[u8]) -> Result<Vec<u8>, SessionError> {
+        match self.algorithm {
+            #[cfg(feature = "zstd-compression")]
+            CompressionAlgorithm::Zstd { level } => {
+                let mut compressed = vec![ZSTD_HEADER];
+                zstd::stream::copy_encode(encoded, &mut compressed, level).map_err(SessionError::codec)?;
+                Ok(compressed)
+            }
+            #[cfg(feature = "lz4-compression")]
+            CompressionAlgorithm::Lz4 => {
+                let mut compressed = vec![LZ4_HEADER];
+                compressed.extend(lz4_flex::compress_prepend_size(encoded));
+                Ok(compressed)
+            }
+        }
+    }
+
+    /// decompresses the value if it starts with the header byte of an enabled compression algorithm
+    fn decompress(encoded: &[u8]) -> Result<Option<Vec<u8>>, SessionError> {
+        match encoded.split_first() {
+            #[cfg(feature = "zstd-compression")]
+            Some((&ZSTD_HEADER, compressed)) => zstd::stream::decode_all(compressed)
+                .map(Some)
+                .map_err(SessionError::codec),
+            #[cfg(feature = "lz4-compression")]
+            Some((&LZ4_HEADER, compressed)) => lz4_flex::decompress_size_prepended(compressed)
+                .map(Some)
+                .map_err(SessionError::codec),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Session;
+    use serde::Deserialize;
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct Value {
+        name: String,
+        count: u32,
+        tags: Vec<String>,
+    }
+
+    fn value() -> Value {
+        Value {
+            name: "value".into(),
+            count: 3,
+            tags: vec!["a".into(), "b".into()],
+        }
+    }
+
+    fn codecs() -> Vec<Codec> {
+        vec![
+            Codec::Json,
+            #[cfg(feature = "msgpack-codec")]
+            Codec::MessagePack,
+            #[cfg(feature = "cbor-codec")]
+            Codec::Cbor,
+            #[cfg(feature = "bincode-codec")]
+            Codec::Bincode,
+        ]
+    }
+
+    #[test]
+    fn codecs_round_trip() {
+        for codec in codecs() {
+            let encoded = codec.encode(&value()).unwrap();
+            assert_eq!(encoded[0], codec.header());
+            assert_eq!(Codec::decode::<Value>(&encoded).unwrap(), value(), "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn json_is_written_without_a_header() {
+        let encoded = Codec::Json.encode(&value()).unwrap();
+        assert_eq!(encoded, serde_json::to_vec(&value()).unwrap());
+    }
+
+    #[test]
+    fn sessions_written_before_codecs_are_decoded() {
+        let session: Session<String> =
+            Codec::decode(br#"{"createdAt":"2024-01-01T00:00:00","value":"value"}"#).unwrap();
+        assert_eq!(session.value, "value");
+        assert_eq!(session.created_at.to_string(), "2024-01-01 00:00:00");
+        assert!(session.idle_timeout.is_none());
+        assert!(session.max_lifetime.is_none());
+    }
+
+    #[test]
+    fn unknown_headers_and_empty_values_are_codec_errors() {
+        assert!(matches!(
+            Codec::decode::<Value>(&[0x7f, 0x00]),
+            Err(SessionError::Codec(_))
+        ));
+        assert!(matches!(Codec::decode::<Value>(&[]), Err(SessionError::Codec(_))));
+        assert!(matches!(
+            Codec::decode::<Value>(b"{not json"),
+            Err(SessionError::Codec(_))
+        ));
+    }
+
+    #[cfg(any(feature = "zstd-compression", feature = "lz4-compression"))]
+    mod compression {
+        use super::*;
+
+        fn compressions() -> Vec<Compression> {
+            vec![
+                #[cfg(feature = "zstd-compression")]
+                Compression::zstd(0),
+                #[cfg(feature = "lz4-compression")]
+                Compression::lz4(),
+            ]
+        }
+
+        fn large_value() -> Value {
+            Value {
+                tags: vec!["tag".into(); 1000],
+                ..value()
+            }
+        }
+
+        #[test]
+        fn compressed_values_round_trip() {
+            for compression in compressions() {
+                for codec in codecs() {
+                    let uncompressed = codec.encode(&large_value()).unwrap();
+                    let compressed = codec.encode_compressed(&large_value(), Some(&compression)).unwrap();
+                    assert!(compressed.len() < uncompressed.len(), "{codec:?} {compression:?}");
+                    assert_eq!(Codec::decode::<Value>(&compressed).unwrap(), large_value());
+                }
+            }
+        }
+
+        #[test]
+        fn values_below_the_threshold_are_not_compressed() {
+            for compression in compressions() {
+                let encoded = Codec::Json.encode_compressed(&value(), Some(&compression)).unwrap();
+                assert_eq!(encoded, Codec::Json.encode(&value()).unwrap());
+
+                let encoded = Codec::Json
+                    .encode_compressed(&value(), Some(&compression.threshold(0)))
+                    .unwrap();
+                assert_ne!(encoded[0], JSON_HEADER);
+                assert_eq!(Codec::decode::<Value>(&encoded).unwrap(), value());
+            }
+        }
+
+        #[test]
+        fn uncompressed_values_are_decoded_alongside_compressed_values() {
+            for codec in codecs() {
+                let encoded = codec.encode_compressed(&large_value(), None).unwrap();
+                assert_eq!(Codec::decode::<Value>(&encoded).unwrap(), large_value());
+            }
+        }
+
+        #[test]
+        fn corrupt_compressed_values_are_codec_errors() {
+            for compression in compressions() {
+                let mut compressed = Codec::Json
+                    .encode_compressed(&large_value(), Some(&compression))
+                    .unwrap();
+                compressed.truncate(compressed.len() / 2);
+                assert!(matches!(
+                    Codec::decode::<Value>(&compressed),
+                    Err(SessionError::Codec(_))
+                ));
+            }
+        }
+    }
+}
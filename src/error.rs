@@ -0,0 +1,101 @@
+use std::fmt::{self, Display};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// error returned by session stores, session decoding and the session layer
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SessionError {
+    /// no session exists for the session id, including sessions whose ttl has run out
+    NotFound,
+    /// the session exists but has outlived its maximum lifetime
+    Expired,
+    /// the session store could not be reached or failed to run an operation
+    Backend(BoxError),
+    /// a session could not be serialized, or a stored session could not be deserialized
+    Codec(BoxError),
+    /// the session's token could not be encoded, decoded or validated, e.g. an expired jwt
+    Token(BoxError),
+    /// a session cookie could not be created
+    Cookie(String),
+    /// the session store was configured with invalid options
+    Config(String),
+    /// the operation is not supported by the session store
+    Unsupported(&'static str),
+    /// the operation was retried but kept conflicting with concurrent modifications of the same session
+    Conflict(&'static str),
+}
+
+impl SessionError {
+    pub fn backend(err: impl Into<BoxError>) -> Self {
+        Self::Backend(err.into())
+    }
+
+    pub fn codec(err: impl Into<BoxError>) -> Self {
+        Self::Codec(err.into())
+    }
+
+    pub fn token(err: impl Into<BoxError>) -> Self {
+        Self::Token(err.into())
+    }
+
+    pub fn cookie(msg: impl Into<String>) -> Self {
+        Self::Cookie(msg.into())
+    }
+
+    pub fn config(msg: impl Into<String>) -> Self {
+        Self::Config(msg.into())
+    }
+
+    /// whether the error is caused by the session store being unavailable rather than by the request's session,
+    /// in which case the request's session can be neither confirmed nor ruled out
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, Self::Backend(_))
+    }
+}
+
+impl Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "session not found"),
+            Self::Expired => write!(f, "session expired"),
+            Self::Backend(err) => write!(f, "session store error: {err}"),
+            Self::Codec(err) => write!(f, "unable to serialize or deserialize session: {err}"),
+            Self::Token(err) => write!(f, "invalid session token: {err}"),
+            Self::Cookie(msg) => write!(f, "unable to create session cookie: {msg}"),
+            Self::Config(msg) => write!(f, "invalid session store config: {msg}"),
+            Self::Unsupported(operation) => write!(f, "{operation} is not supported by this session store"),
+            Self::Conflict(operation) => write!(f, "unable to {operation} due to concurrent modifications"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Backend(err) | Self::Codec(err) | Self::Token(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "postgres-backend")]
+impl From<tokio_postgres::Error> for SessionError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        Self::backend(err)
+    }
+}
+
+#[cfg(feature = "redis-backend")]
+impl From<redis_cluster_async::redis::RedisError> for SessionError {
+    fn from(err: redis_cluster_async::redis::RedisError) -> Self {
+        Self::backend(err)
+    }
+}
+
+#[cfg(feature = "sqlite-backend")]
+impl From<rusqlite::Error> for SessionError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::backend(err)
+    }
+}
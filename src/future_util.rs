@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-use http::{Response, StatusCode};
+use http::{header::WWW_AUTHENTICATE, HeaderValue, Response, StatusCode};
 use pin_project_lite::pin_project;
 use std::task::{Context, Poll};
 use std::{future::Future, pin::Pin};
@@ -19,11 +19,28 @@ impl<F, B: Default> ResponseFuture<F, B> {
         }
     }
 
-    pub fn invalid_auth() -> Self {
+    pub fn invalid_auth(www_authenticate: HeaderValue) -> Self {
         let mut res = Response::new(B::default());
         *res.status_mut() = StatusCode::UNAUTHORIZED;
+        res.headers_mut().insert(WWW_AUTHENTICATE, www_authenticate);
+        Self::response(res)
+    }
+
+    pub fn service_unavailable() -> Self {
+        Self::status(StatusCode::SERVICE_UNAVAILABLE)
+    }
+
+    pub fn status(status: StatusCode) -> Self {
+        let mut res = Response::new(B::default());
+        *res.status_mut() = status;
+        Self::response(res)
+    }
+
+    pub fn response(response: Response<B>) -> Self {
         Self {
-            kind: ResponseFutureKind::Error { response: Some(res) },
+            kind: ResponseFutureKind::Error {
+                response: Some(response),
+            },
         }
     }
 }
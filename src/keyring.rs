@@ -0,0 +1,191 @@
+use chrono::{NaiveDateTime, Utc};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::error::Unspecified;
+use ring::hkdf::{Salt, HKDF_SHA256};
+use ring::hmac::{sign, verify, Key, Tag, HMAC_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// id given to the key of a keyring created from a single secret
+pub const DEFAULT_KEY_ID: &str = "default";
+
+// the cookie encryption key is derived from the same secret as the signing key so that rotating
+// the secret rotates both
+const ENCRYPTION_KEY_SALT: &[u8] = b"session-util";
+const ENCRYPTION_KEY_INFO: &[u8] = b"cookie encryption";
+
+/// key used to sign, encrypt and verify session cookies, an aes-256-gcm key for encrypted cookies is
+/// derived from the same secret as the hmac key
+#[derive(Clone)]
+pub struct SigningKey {
+    id: String,
+    key: Key,
+    cipher: LessSafeKey,
+    accept_until: Option<NaiveDateTime>,
+}
+
+impl SigningKey {
+    pub fn new(id: impl Into<String>, secret: impl AsRef<[u8]>) -> Self {
+        let prk = Salt::new(HKDF_SHA256, ENCRYPTION_KEY_SALT).extract(secret.as_ref());
+        let encryption_key = prk
+            .expand(&[ENCRYPTION_KEY_INFO], &AES_256_GCM)
+            // only fails if the requested key length is more than 255 times the hkdf digest length
+            .expect("aes-256-gcm key length is a valid hkdf output length");
+        let cipher = LessSafeKey::new(UnboundKey::from(encryption_key));
+        Self {
+            id: id.into(),
+            key: Key::new(HMAC_SHA256, secret.as_ref()),
+            cipher,
+            accept_until: None,
+        }
+    }
+
+    /// identifies the key, e.g. so that logs can show which key verified a cookie
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+
+    /// when a retired key stops being accepted, retired keys without one are accepted until removed from the keyring
+    pub fn accept_until(&self) -> Option<NaiveDateTime> {
+        self.accept_until
+    }
+
+    fn is_accepted(&self, now: NaiveDateTime) -> bool {
+        !matches!(self.accept_until, Some(accept_until) if accept_until <= now)
+    }
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SigningKey")
+            .field("id", &self.id)
+            .field("accept_until", &self.accept_until)
+            .finish_non_exhaustive()
+    }
+}
+
+/// set of keys used to sign and verify session cookies
+///
+/// new cookies are always signed or encrypted with the primary key while cookies from a retired key
+/// are still accepted until the key's grace period ends, so that the secret can be rotated
+/// without signing every user out at once
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    primary: SigningKey,
+    retired: Vec<SigningKey>,
+    encrypt_cookies: bool,
+}
+
+impl Keyring {
+    pub fn new(primary: SigningKey) -> Self {
+        Self {
+            primary,
+            retired: vec![],
+            encrypt_cookies: false,
+        }
+    }
+
+    /// whether new cookies are encrypted rather than only signed, cookies in either format are accepted
+    /// regardless so that a deployment can migrate between the two
+    pub fn encrypt_cookies(mut self, encrypt_cookies: bool) -> Self {
+        self.encrypt_cookies = encrypt_cookies;
+        self
+    }
+
+    pub fn encrypts_cookies(&self) -> bool {
+        self.encrypt_cookies
+    }
+
+    /// adds a previously used key which is only accepted for verification, until `accept_until` if provided
+    pub fn retire(mut self, mut key: SigningKey, accept_until: impl Into<Option<NaiveDateTime>>) -> Self {
+        key.accept_until = accept_until.into();
+        self.retired.push(key);
+        self
+    }
+
+    pub fn primary(&self) -> &SigningKey {
+        &self.primary
+    }
+
+    pub fn retired(&self) -> &[SigningKey] {
+        &self.retired
+    }
+
+    /// signs the message with the primary key
+    pub fn sign(&self, message: &[u8]) -> Tag {
+        sign(&self.primary.key, message)
+    }
+
+    /// verifies the signature against the primary key and then against each retired key still within
+    /// its grace period, returning the key which produced the signature
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> Option<&SigningKey> {
+        let now = Utc::now().naive_utc();
+        self.accepted_keys(now)
+            .find(|key| verify(&key.key, message, signature).is_ok())
+    }
+
+    /// encrypts the plaintext with the primary key, returning the random nonce followed by the ciphertext and tag
+    pub fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Unspecified> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce)?;
+
+        let mut in_out = plaintext.to_vec();
+        self.primary.cipher.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            &mut in_out,
+        )?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(in_out);
+        Ok(sealed)
+    }
+
+    /// decrypts a value produced by `encrypt` with the primary key or any retired key still within its
+    /// grace period, returning the plaintext and the key which encrypted it
+    pub fn decrypt(&self, aad: &[u8], sealed: &[u8]) -> Option<(Vec<u8>, &SigningKey)> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let now = Utc::now().naive_utc();
+        self.accepted_keys(now).find_map(|key| {
+            let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+            let mut in_out = ciphertext.to_vec();
+            let plaintext_len = key.cipher.open_in_place(nonce, Aad::from(aad), &mut in_out).ok()?.len();
+            in_out.truncate(plaintext_len);
+            Some((in_out, key))
+        })
+    }
+
+    fn accepted_keys(&self, now: NaiveDateTime) -> impl Iterator<Item = &SigningKey> {
+        std::iter::once(&self.primary).chain(self.retired.iter().filter(move |key| key.is_accepted(now)))
+    }
+}
+
+impl From<SigningKey> for Keyring {
+    fn from(primary: SigningKey) -> Self {
+        Self::new(primary)
+    }
+}
+
+impl From<&str> for Keyring {
+    fn from(secret: &str) -> Self {
+        Self::new(SigningKey::new(DEFAULT_KEY_ID, secret))
+    }
+}
+
+impl From<&String> for Keyring {
+    fn from(secret: &String) -> Self {
+        Self::from(secret.as_str())
+    }
+}
+
+impl From<String> for Keyring {
+    fn from(secret: String) -> Self {
+        Self::from(secret.as_str())
+    }
+}
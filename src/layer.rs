@@ -1,41 +1,120 @@
 use crate::*;
-use data_encoding::BASE64;
-use futures::future::{BoxFuture, FutureExt};
-use http::{header::COOKIE, Request, Response};
-use ring::hmac::verify;
+use chrono::Duration;
+use futures::future::{self, BoxFuture, FutureExt};
+use http::{header::COOKIE, HeaderValue, Request, Response, StatusCode};
 use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use tower_layer::Layer;
 use tower_service::Service;
 use uuid::Uuid;
 
-#[derive(Clone)]
-pub struct SessionService<I, P, S, K, V> {
+pub struct SessionService<I, P, S, K, V, B> {
     pub inner: I,
-    pub layer: SessionLayer<P, S, K, V>,
+    pub layer: SessionLayer<P, S, K, V, B>,
 }
 
-pub struct SessionLayer<P, S, K, V> {
+impl<I: Clone, S: Clone, K: Clone, V: Clone, P, B> Clone for SessionService<I, P, S, K, V, B> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            layer: self.layer.clone(),
+        }
+    }
+}
+
+pub struct SessionLayer<P, S, K, V, B> {
     pub key: K,
     pub validation: V,
     pub store: S,
+    /// when set, each request with a session resets the session's ttl to this duration,
+    /// sessions with their own idle timeout are always reset to their idle timeout
+    pub sliding_expiration: Option<Duration>,
+    /// how requests are handled when their session cannot be loaded or decoded
+    pub failure_policy: FailurePolicy<B>,
     pub _encoded: PhantomData<P>,
 }
 
-impl<S: Clone, K: Clone, V: Clone, P> Clone for SessionLayer<P, S, K, V> {
+/// how the session layer handles requests whose session cannot be loaded from the store or decoded
+///
+/// requests without a session, or whose session is missing from the store or has expired, always continue
+/// without a session, the policy only applies to errors
+#[derive(Default)]
+pub enum FailurePolicy<B> {
+    /// continue without a session, including when the store is unavailable
+    Anonymous,
+    /// continue without a session when it is invalid, but respond with `503 Service Unavailable` when the
+    /// store is unavailable, the default
+    #[default]
+    ServiceUnavailable,
+    /// respond with an empty body and the provided status
+    Status(StatusCode),
+    /// respond with the response built by the handler, or continue without a session when it returns `None`
+    Handler(FailureHandler<B>),
+}
+
+pub type FailureHandler<B> = Arc<dyn Fn(&SessionError) -> Option<Response<B>> + Send + Sync>;
+
+impl<B> FailurePolicy<B> {
+    pub fn handler<F>(handler: F) -> Self
+    where
+        F: Fn(&SessionError) -> Option<Response<B>> + Send + Sync + 'static,
+    {
+        Self::Handler(Arc::new(handler))
+    }
+
+    fn response_future<F>(&self, err: &SessionError) -> Option<ResponseFuture<F, B>>
+    where
+        B: Default,
+    {
+        match self {
+            Self::Anonymous => None,
+            Self::ServiceUnavailable => err.is_unavailable().then(ResponseFuture::service_unavailable),
+            Self::Status(status) => Some(ResponseFuture::status(*status)),
+            Self::Handler(handler) => handler(err).map(ResponseFuture::response),
+        }
+    }
+}
+
+impl<B> Clone for FailurePolicy<B> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Anonymous => Self::Anonymous,
+            Self::ServiceUnavailable => Self::ServiceUnavailable,
+            Self::Status(status) => Self::Status(*status),
+            Self::Handler(handler) => Self::Handler(handler.clone()),
+        }
+    }
+}
+
+impl<B> Debug for FailurePolicy<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Anonymous => write!(f, "Anonymous"),
+            Self::ServiceUnavailable => write!(f, "ServiceUnavailable"),
+            Self::Status(status) => f.debug_tuple("Status").field(status).finish(),
+            Self::Handler(_) => write!(f, "Handler"),
+        }
+    }
+}
+
+impl<S: Clone, K: Clone, V: Clone, P, B> Clone for SessionLayer<P, S, K, V, B> {
     fn clone(&self) -> Self {
         Self {
             key: self.key.clone(),
             validation: self.validation.clone(),
             store: self.store.clone(),
+            sliding_expiration: self.sliding_expiration,
+            failure_policy: self.failure_policy.clone(),
             _encoded: PhantomData,
         }
     }
 }
 
-impl<I, P, S: Clone, K: Clone, V: Clone> Layer<I> for SessionLayer<P, S, K, V> {
-    type Service = SessionService<I, P, S, K, V>;
+impl<I, P, S: Clone, K: Clone, V: Clone, B> Layer<I> for SessionLayer<P, S, K, V, B> {
+    type Service = SessionService<I, P, S, K, V, B>;
     fn layer(&self, inner: I) -> Self::Service {
         SessionService {
             layer: self.clone(),
@@ -44,7 +123,7 @@ impl<I, P, S: Clone, K: Clone, V: Clone> Layer<I> for SessionLayer<P, S, K, V> {
     }
 }
 
-impl<P, S, K, V> SessionLayer<P, S, K, V>
+impl<P, S, K, V, B> SessionLayer<P, S, K, V, B>
 where
     S: SessionStore,
     Session<<S as SessionStore>::Value>: RawSession<P, Key = K, Validation = V>,
@@ -54,27 +133,51 @@ where
             store,
             key,
             validation,
+            sliding_expiration: None,
+            failure_policy: FailurePolicy::default(),
             _encoded: PhantomData,
         }
     }
 }
 
-impl<P, S> SessionLayer<P, S, (), ()> {
+impl<P, S, B> SessionLayer<P, S, (), (), B> {
     pub fn plain(store: S) -> Self {
         SessionLayer {
             store,
             key: (),
             validation: (),
+            sliding_expiration: None,
+            failure_policy: FailurePolicy::default(),
             _encoded: PhantomData,
         }
     }
 }
 
+impl<P, S, K, V, B> SessionLayer<P, S, K, V, B> {
+    /// extends the ttl of a request's session by `extend_by` on every request it is used in,
+    /// so that sessions expire after a period of inactivity rather than at a fixed time
+    ///
+    /// only the ttl in the session store is extended, cookies issued for these sessions should
+    /// not set a `max_age` or `expires` which would otherwise end the session in the browser
+    pub fn sliding_expiration(mut self, extend_by: Duration) -> Self {
+        self.sliding_expiration = Some(extend_by);
+        self
+    }
+
+    /// sets how requests are handled when their session cannot be loaded or decoded,
+    /// defaults to `FailurePolicy::ServiceUnavailable`
+    pub fn failure_policy(mut self, failure_policy: FailurePolicy<B>) -> Self {
+        self.failure_policy = failure_policy;
+        self
+    }
+}
+
 // TODO: reimplement with no clone or 'static bounds once Service::Future is generic
-impl<ReqBody, ResBody, I, P, S, K, V, R> Service<Request<ReqBody>> for SessionService<I, P, S, K, V>
+impl<ReqBody, ResBody, I, P, S, K, V, R> Service<Request<ReqBody>> for SessionService<I, P, S, K, V, ResBody>
 where
     I: Clone + Service<Request<ReqBody>, Response = Response<ResBody>> + Send + 'static,
     <I as Service<Request<ReqBody>>>::Future: Send,
+    <I as Service<Request<ReqBody>>>::Error: Send,
     S: Clone + SessionStore<Value = R>,
     K: Clone + Send + 'static,
     V: Clone + Send + 'static,
@@ -99,27 +202,167 @@ where
 
         let Self { mut inner, layer } = self.clone();
         let SessionLayer {
-            key, validation, store, ..
+            key,
+            validation,
+            store,
+            sliding_expiration,
+            failure_policy,
+            ..
         } = layer;
 
         async move {
             match request_session {
-                Ok(RequestSession::None) => Ok(None),
-                Ok(RequestSession::SessionId(session_id)) => store.get(&session_id).await.map(Some),
-                Ok(RequestSession::Session(session)) => Ok(Some(session)),
+                Ok(RequestSession::None) => Ok((None, None, None)),
+                Ok(RequestSession::Cookie(session_cookie)) => {
+                    let session_id = session_cookie.session_id;
+                    let session = match store.get(&session_id).await.and_then(Session::unexpired) {
+                        Ok(session) => session,
+                        // sessions which are missing or have expired leave the request without a session
+                        Err(SessionError::NotFound | SessionError::Expired) => return Ok((None, None, None)),
+                        Err(err) => return Err(err),
+                    };
+                    // the session is still valid for this request even if its ttl could not be extended,
+                    // so its ttl is extended while the request is handled rather than before
+                    let touch = session.idle_ttl(sliding_expiration).map(|idle_ttl| async move {
+                        if let Err(err) = store.touch(&session_id, idle_ttl).await {
+                            log::warn!("unable to extend the ttl of session {session_id}: {err}");
+                        }
+                    });
+                    Ok((Some(session), Some(session_cookie), touch))
+                }
+                Ok(RequestSession::Session(session)) => Ok((Some(session), None, None)),
                 Err(err) => Err(err),
             }
         }
-        .map(move |session| {
-            Session::<R>::add_extensions(session, &key, &validation, req.extensions_mut());
-            ResponseFuture::future(inner.call(req))
+        .map(move |result| {
+            let (session, session_cookie, touch) = match result {
+                Ok((session, session_cookie, touch)) => (Ok(session), session_cookie, touch),
+                Err(err) => (Err(err), None, None),
+            };
+            let result = Session::<R>::add_extensions(session, &key, &validation, req.extensions_mut());
+            if let (Ok(()), Some(session_cookie)) = (&result, session_cookie) {
+                // lets handlers see which key verified the cookie and read an encrypted cookie's payload
+                req.extensions_mut().insert(session_cookie);
+            }
+            match result.err().and_then(|err| failure_policy.response_future(&err)) {
+                Some(response_future) => response_future,
+                None => ResponseFuture::future(match touch {
+                    Some(touch) => future::join(touch, inner.call(req)).map(|(_, res)| res).boxed(),
+                    None => inner.call(req).boxed(),
+                }),
+            }
         })
         .flatten()
         .boxed()
     }
 }
 
-pub(crate) fn get_session_id_from_request<S: SessionStore, ReqBody>(store: &S, req: &Request<ReqBody>) -> Option<Uuid> {
+/// rejects requests without a session with `401 Unauthorized` and a `WWW-Authenticate` header, must be applied
+/// within a `SessionLayer` with the same session type `P`, e.g. to the protected routes of a router whose other
+/// routes accept anonymous requests
+pub struct RequireSessionLayer<P> {
+    pub www_authenticate: HeaderValue,
+    pub _session: PhantomData<P>,
+}
+
+pub struct RequireSessionService<I, P> {
+    pub inner: I,
+    pub layer: RequireSessionLayer<P>,
+}
+
+impl<I: Clone, P> Clone for RequireSessionService<I, P> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            layer: self.layer.clone(),
+        }
+    }
+}
+
+impl<P> Clone for RequireSessionLayer<P> {
+    fn clone(&self) -> Self {
+        Self {
+            www_authenticate: self.www_authenticate.clone(),
+            _session: PhantomData,
+        }
+    }
+}
+
+impl<P> Default for RequireSessionLayer<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<P> RequireSessionLayer<P> {
+    /// responds with the `WWW-Authenticate` challenge `Cookie`
+    pub fn new() -> Self {
+        Self {
+            www_authenticate: HeaderValue::from_static("Cookie"),
+            _session: PhantomData,
+        }
+    }
+
+    /// sets the challenge sent in the `WWW-Authenticate` header, e.g. `Bearer` for account sessions which are
+    /// authenticated with a jwt header
+    pub fn www_authenticate(mut self, www_authenticate: HeaderValue) -> Self {
+        self.www_authenticate = www_authenticate;
+        self
+    }
+}
+
+impl<I, P> Layer<I> for RequireSessionLayer<P> {
+    type Service = RequireSessionService<I, P>;
+    fn layer(&self, inner: I) -> Self::Service {
+        RequireSessionService {
+            layer: self.clone(),
+            inner,
+        }
+    }
+}
+
+impl<ReqBody, ResBody, I, P> Service<Request<ReqBody>> for RequireSessionService<I, P>
+where
+    I: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    P: Send + Sync + 'static,
+    ResBody: Default,
+{
+    type Response = Response<ResBody>;
+    type Error = I::Error;
+    type Future = ResponseFuture<I::Future, ResBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // the session layer inserts `Option<P>`, which is `None` for requests without a valid session
+        match req.extensions().get::<Option<P>>() {
+            Some(Some(_)) => ResponseFuture::future(self.inner.call(req)),
+            _ => ResponseFuture::invalid_auth(self.layer.www_authenticate.clone()),
+        }
+    }
+}
+
+/// session id of a request's session cookie along with the id of the key which verified it, inserted into the
+/// request's extensions by the session layer alongside the session
+#[derive(Clone, Debug)]
+pub struct SessionCookie {
+    pub session_id: Uuid,
+    pub key_id: String,
+    /// payload carried by an encrypted cookie
+    pub payload: Option<Vec<u8>>,
+}
+
+impl SessionCookie {
+    /// whether the cookie was signed with a retired key and should be reissued with the primary key
+    pub fn is_retired<S: SessionStore + ?Sized>(&self, store: &S) -> bool {
+        self.key_id != store.keyring().primary().id()
+    }
+}
+
+/// finds the request's session cookie and verifies its signature, or decrypts it, with the store's keyring
+pub fn verify_session_cookie<S: SessionStore, ReqBody>(store: &S, req: &Request<ReqBody>) -> Option<SessionCookie> {
     req.headers()
         .get_all(COOKIE)
         .iter()
@@ -134,11 +377,440 @@ pub(crate) fn get_session_id_from_request<S: SessionStore, ReqBody>(store: &S, r
             if name != store.key_name() {
                 return None;
             }
-            let cookie_value: CookieValue = serde_plain::from_str(value).ok()?;
-
-            let signature = BASE64.decode(cookie_value.signature.as_bytes()).ok()?;
-            verify(store.key(), cookie_value.id.as_bytes(), &signature).ok()?;
+            let (cookie_value, key) = CookieValue::decode(value, store.keyring())?;
 
-            Some(cookie_value.id)
+            Some(SessionCookie {
+                session_id: cookie_value.id,
+                key_id: key.id().to_string(),
+                payload: cookie_value.payload,
+            })
         })
 }
+
+#[cfg(all(test, feature = "memory-backend"))]
+mod tests {
+    use super::*;
+    use ::futures::future::{ready, Ready};
+    use ::http::{
+        header::{SET_COOKIE, WWW_AUTHENTICATE},
+        HeaderMap,
+    };
+    use ::std::convert::Infallible;
+
+    #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+    struct Value(String);
+
+    impl SessionValue<(), MemoryStore<Value>> for Value {}
+
+    /// responds with the session and session cookie the session layer inserted into the request's extensions
+    #[derive(Clone)]
+    struct Extensions;
+
+    impl Service<Request<()>> for Extensions {
+        type Response = Response<()>;
+        type Error = Infallible;
+        type Future = Ready<Result<Response<()>, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<()>) -> Self::Future {
+            let mut res = Response::new(());
+            if let Some(session) = req.extensions().get::<Option<Session<Value>>>() {
+                res.extensions_mut().insert(session.clone());
+            }
+            if let Some(session_cookie) = req.extensions().get::<SessionCookie>() {
+                res.extensions_mut().insert(session_cookie.clone());
+            }
+            ready(Ok(res))
+        }
+    }
+
+    fn store() -> MemoryStore<Value> {
+        memory_store(MemoryStoreConfig::builder().key_name("sid").key("secret").build()).unwrap()
+    }
+
+    /// store whose `get` fails with the provided error, its sessions are stored in and signed by a memory store
+    #[derive(Clone, Debug)]
+    struct Failing {
+        store: MemoryStore<Value>,
+        err: fn() -> SessionError,
+    }
+
+    #[async_trait]
+    impl SessionStore for Failing {
+        type Value = Value;
+
+        fn keyring(&self) -> &Keyring {
+            self.store.keyring()
+        }
+        fn key_name(&self) -> &str {
+            self.store.key_name()
+        }
+
+        async fn set(
+            &self,
+            prefix: Option<String>,
+            session_id: &Uuid,
+            session: &Session<Value>,
+        ) -> Result<(), SessionError> {
+            self.store.set(prefix, session_id, session).await
+        }
+        async fn get(&self, _: &Uuid) -> Result<Session<Value>, SessionError> {
+            Err((self.err)())
+        }
+        async fn delete(&self, session_id: &Uuid) -> Result<(), SessionError> {
+            self.store.delete(session_id).await
+        }
+    }
+
+    impl SessionValue<(), Failing> for Value {}
+
+    /// a failing store and the cookie of a session stored in it
+    async fn failing(err: fn() -> SessionError) -> (Failing, String) {
+        let store = store();
+        let cookie = cookie(&store, CookieConfig::new(&Value("value".into()))).await;
+        (Failing { store, err }, cookie)
+    }
+
+    fn unavailable() -> SessionError {
+        SessionError::backend("connection refused")
+    }
+
+    fn undecodable() -> SessionError {
+        SessionError::codec("invalid session")
+    }
+
+    async fn cookie(store: &MemoryStore<Value>, cookie_config: CookieConfig<'_, Value>) -> String {
+        let mut headers = HeaderMap::new();
+        store
+            .store_session_and_set_cookie(&mut headers, cookie_config, None)
+            .await
+            .unwrap();
+        let set_cookie = headers.get(SET_COOKIE).unwrap().to_str().unwrap();
+        set_cookie.split(';').next().unwrap().to_string()
+    }
+
+    async fn call<S>(layer: &SessionLayer<Session<Value>, S, (), (), ()>, cookie: Option<&str>) -> Response<()>
+    where
+        S: Clone + SessionStore<Value = Value>,
+        Value: SessionValue<(), S>,
+    {
+        let mut req = Request::builder();
+        if let Some(cookie) = cookie {
+            req = req.header(COOKIE, cookie);
+        }
+        layer.layer(Extensions).call(req.body(()).unwrap()).await.unwrap()
+    }
+
+    /// calls a session layer wrapping a layer which requires the session
+    async fn call_required(
+        layer: &SessionLayer<Session<Value>, MemoryStore<Value>, (), (), ()>,
+        require: RequireSessionLayer<Session<Value>>,
+        cookie: Option<&str>,
+    ) -> Response<()> {
+        let mut req = Request::builder();
+        if let Some(cookie) = cookie {
+            req = req.header(COOKIE, cookie);
+        }
+        let mut service = layer.layer(require.layer(Extensions));
+        service.call(req.body(()).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_cookie_have_no_session() {
+        let layer = SessionLayer::plain(store());
+
+        let res = call(&layer, None).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(matches!(res.extensions().get::<Option<Session<Value>>>(), Some(None)));
+        assert!(res.extensions().get::<SessionCookie>().is_none());
+    }
+
+    #[tokio::test]
+    async fn session_and_session_cookie_are_inserted() {
+        let store = store();
+        let value = Value("value".into());
+        let cookie = cookie(&store, CookieConfig::new(&value)).await;
+        let layer = SessionLayer::plain(store);
+
+        let res = call(&layer, Some(&cookie)).await;
+        let session = res
+            .extensions()
+            .get::<Option<Session<Value>>>()
+            .cloned()
+            .flatten()
+            .unwrap();
+        assert_eq!(session.value, value);
+        let session_cookie = res.extensions().get::<SessionCookie>().unwrap();
+        assert_eq!(session_cookie.session_id, session.session_id);
+        assert_eq!(session_cookie.key_id, DEFAULT_KEY_ID);
+        assert!(session_cookie.payload.is_none());
+    }
+
+    #[tokio::test]
+    async fn cookies_without_a_stored_session_have_no_session() {
+        let store = store();
+        let value = Value("value".into());
+        let cookie = cookie(&store, CookieConfig::new(&value)).await;
+        let session_id = verify_session_cookie(&store, &Request::builder().header(COOKIE, &cookie).body(()).unwrap())
+            .unwrap()
+            .session_id;
+        store.delete(&session_id).await.unwrap();
+        let layer = SessionLayer::plain(store);
+
+        let res = call(&layer, Some(&cookie)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(matches!(res.extensions().get::<Option<Session<Value>>>(), Some(None)));
+        assert!(res.extensions().get::<SessionCookie>().is_none());
+    }
+
+    #[tokio::test]
+    async fn cookies_signed_with_another_key_are_ignored() {
+        let value = Value("value".into());
+        let cookie = cookie(
+            &memory_store(MemoryStoreConfig::builder().key_name("sid").key("other secret").build()).unwrap(),
+            CookieConfig::new(&value),
+        )
+        .await;
+        let layer = SessionLayer::plain(store());
+
+        let res = call(&layer, Some(&cookie)).await;
+        assert!(matches!(res.extensions().get::<Option<Session<Value>>>(), Some(None)));
+        assert!(res.extensions().get::<SessionCookie>().is_none());
+    }
+
+    #[tokio::test]
+    async fn sliding_expiration_extends_the_session_ttl() {
+        let store = store();
+        let value = Value("value".into());
+        let cookie = cookie(&store, CookieConfig::new(&value).max_age(Duration::milliseconds(100))).await;
+        let layer = SessionLayer::plain(store.clone()).sliding_expiration(Duration::hours(1));
+
+        call(&layer, Some(&cookie)).await;
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        let res = call(&layer, Some(&cookie)).await;
+        assert!(matches!(
+            res.extensions().get::<Option<Session<Value>>>(),
+            Some(Some(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn sessions_expire_without_sliding_expiration() {
+        let store = store();
+        let value = Value("value".into());
+        let cookie = cookie(&store, CookieConfig::new(&value).max_age(Duration::milliseconds(100))).await;
+        let layer = SessionLayer::plain(store.clone());
+
+        call(&layer, Some(&cookie)).await;
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        let res = call(&layer, Some(&cookie)).await;
+        assert!(matches!(res.extensions().get::<Option<Session<Value>>>(), Some(None)));
+    }
+
+    #[tokio::test]
+    async fn unavailable_stores_respond_with_service_unavailable_by_default() {
+        let (store, cookie) = failing(unavailable).await;
+        let layer = SessionLayer::plain(store);
+
+        let res = call(&layer, Some(&cookie)).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        // the inner service is not called
+        assert!(res.extensions().get::<Option<Session<Value>>>().is_none());
+    }
+
+    #[tokio::test]
+    async fn undecodable_sessions_have_no_session_by_default() {
+        let (store, cookie) = failing(undecodable).await;
+        let layer = SessionLayer::plain(store);
+
+        let res = call(&layer, Some(&cookie)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(matches!(res.extensions().get::<Option<Session<Value>>>(), Some(None)));
+    }
+
+    #[tokio::test]
+    async fn anonymous_policy_continues_without_a_session() {
+        for err in [unavailable, undecodable] {
+            let (store, cookie) = failing(err).await;
+            let layer = SessionLayer::plain(store).failure_policy(FailurePolicy::Anonymous);
+
+            let res = call(&layer, Some(&cookie)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(matches!(res.extensions().get::<Option<Session<Value>>>(), Some(None)));
+        }
+    }
+
+    #[tokio::test]
+    async fn status_policy_responds_with_the_status() {
+        for err in [unavailable, undecodable] {
+            let (store, cookie) = failing(err).await;
+            let layer = SessionLayer::plain(store).failure_policy(FailurePolicy::Status(StatusCode::UNAUTHORIZED));
+
+            let res = call(&layer, Some(&cookie)).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert!(res.extensions().get::<Option<Session<Value>>>().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_policy_responds_with_the_handlers_response() {
+        let policy = FailurePolicy::handler(|err: &SessionError| {
+            err.is_unavailable()
+                .then(|| Response::builder().status(StatusCode::BAD_GATEWAY).body(()).unwrap())
+        });
+
+        let (store, cookie) = failing(unavailable).await;
+        let layer = SessionLayer::plain(store).failure_policy(policy.clone());
+        let res = call(&layer, Some(&cookie)).await;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert!(res.extensions().get::<Option<Session<Value>>>().is_none());
+
+        // requests continue without a session when the handler returns no response
+        let (store, cookie) = failing(undecodable).await;
+        let layer = SessionLayer::plain(store).failure_policy(policy);
+        let res = call(&layer, Some(&cookie)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(matches!(res.extensions().get::<Option<Session<Value>>>(), Some(None)));
+    }
+
+    #[tokio::test]
+    async fn required_sessions_reject_requests_without_a_session() {
+        let store = store();
+        let value = Value("value".into());
+        let cookie = cookie(&store, CookieConfig::new(&value)).await;
+        let session_id = verify_session_cookie(&store, &Request::builder().header(COOKIE, &cookie).body(()).unwrap())
+            .unwrap()
+            .session_id;
+        let layer = SessionLayer::plain(store.clone());
+
+        let res = call_required(&layer, RequireSessionLayer::new(), None).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()[WWW_AUTHENTICATE], "Cookie");
+        assert!(res.extensions().get::<Option<Session<Value>>>().is_none());
+
+        // sessions missing from the store are rejected along with requests without a cookie
+        store.delete(&session_id).await.unwrap();
+        let require = RequireSessionLayer::new().www_authenticate(HeaderValue::from_static("Bearer"));
+        let res = call_required(&layer, require, Some(&cookie)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()[WWW_AUTHENTICATE], "Bearer");
+    }
+
+    #[tokio::test]
+    async fn required_sessions_pass_requests_with_a_session() {
+        let store = store();
+        let value = Value("value".into());
+        let cookie = cookie(&store, CookieConfig::new(&value)).await;
+        let layer = SessionLayer::plain(store);
+
+        let res = call_required(&layer, RequireSessionLayer::new(), Some(&cookie)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get(WWW_AUTHENTICATE).is_none());
+        let session = res.extensions().get::<Option<Session<Value>>>().cloned().flatten();
+        assert_eq!(session.unwrap().value, value);
+    }
+
+    #[cfg(feature = "account-session")]
+    mod account_session {
+        use super::*;
+        use ::jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+
+        #[derive(Clone, Debug, Deserialize, Serialize)]
+        struct Fields {
+            role: String,
+        }
+
+        type Account = AccountSession<String, Fields>;
+
+        /// responds with whether the session layer inserted a decoded account session
+        #[derive(Clone)]
+        struct Accounts;
+
+        impl Service<Request<()>> for Accounts {
+            type Response = Response<()>;
+            type Error = Infallible;
+            type Future = Ready<Result<Response<()>, Infallible>>;
+
+            fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn call(&mut self, req: Request<()>) -> Self::Future {
+                let mut res = Response::new(());
+                if let Some(session) = req.extensions().get::<Option<Account>>() {
+                    res.extensions_mut().insert(session.clone());
+                }
+                ready(Ok(res))
+            }
+        }
+
+        fn token(secret: &[u8]) -> AccountSessionToken<()> {
+            let state = AccountSessionState {
+                account_id: "account".to_string(),
+                fields: Fields { role: "admin".into() },
+            };
+            AccountSessionClaims::new_exp_in(state, "issuer", Duration::hours(1))
+                .encode(&Header::default(), &EncodingKey::from_secret(secret))
+                .unwrap()
+        }
+
+        async fn call(store: &MemoryStore<AccountSessionToken<()>>, req: Request<()>) -> Response<()> {
+            let layer = SessionLayer::<Account, _, _, _, ()>::encoded(
+                store.clone(),
+                DecodingKey::from_secret(b"jwt secret"),
+                Validation::default(),
+            );
+            let require = RequireSessionLayer::<Account>::new().www_authenticate(HeaderValue::from_static("Bearer"));
+            layer.layer(require.layer(Accounts)).call(req).await.unwrap()
+        }
+
+        #[tokio::test]
+        async fn required_account_sessions_are_decoded() {
+            let store = memory_store(MemoryStoreConfig::builder().key_name("sid").key("secret").build()).unwrap();
+
+            let req = Request::builder()
+                .header(HTTP_ACCOUNT_SESSION_JWT_HEADER, token(b"jwt secret").token)
+                .body(())
+                .unwrap();
+            let res = call(&store, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let session = res.extensions().get::<Option<Account>>().cloned().flatten().unwrap();
+            assert_eq!(session.account_id(), "account");
+            assert_eq!(session.fields().role, "admin");
+
+            let mut headers = HeaderMap::new();
+            let token = token(b"jwt secret");
+            store
+                .store_session_and_set_cookie(&mut headers, CookieConfig::new(&token), None)
+                .await
+                .unwrap();
+            let cookie = headers[SET_COOKIE].to_str().unwrap().split(';').next().unwrap();
+            let res = call(&store, Request::builder().header(COOKIE, cookie).body(()).unwrap()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(matches!(res.extensions().get::<Option<Account>>(), Some(Some(_))));
+        }
+
+        #[tokio::test]
+        async fn required_account_sessions_reject_requests_without_a_valid_token() {
+            let store = memory_store(MemoryStoreConfig::builder().key_name("sid").key("secret").build()).unwrap();
+
+            let res = call(&store, Request::builder().body(()).unwrap()).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(res.headers()[WWW_AUTHENTICATE], "Bearer");
+
+            // tokens which cannot be decoded leave the request without an account session
+            let req = Request::builder()
+                .header(HTTP_ACCOUNT_SESSION_JWT_HEADER, token(b"other secret").token)
+                .body(())
+                .unwrap();
+            let res = call(&store, req).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            assert!(res.extensions().get::<Option<Account>>().is_none());
+        }
+    }
+}
@@ -7,7 +7,10 @@ extern crate serde;
 
 mod _cookie;
 mod backends;
+mod codec;
+mod error;
 mod future_util;
+mod keyring;
 mod layer;
 mod session;
 mod store;
@@ -16,7 +19,10 @@ mod util;
 
 pub use _cookie::*;
 pub use backends::*;
+pub use codec::*;
+pub use error::*;
 pub use future_util::*;
+pub use keyring::*;
 pub use layer::*;
 pub use session::*;
 pub use store::*;
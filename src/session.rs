@@ -1,5 +1,5 @@
-use ::anyhow::Error;
-use ::chrono::{Duration, NaiveDateTime};
+use crate::{SessionCookie, SessionError};
+use ::chrono::{Duration, NaiveDateTime, Utc};
 use ::http::Extensions;
 use ::std::ops::Deref;
 use ::uuid::Uuid;
@@ -15,9 +15,58 @@ pub struct Session<T> {
     pub max_age: Option<Duration>,
     #[serde(skip)]
     pub expires: Option<NaiveDateTime>,
+    /// how long the session may go unused before it expires
+    #[serde(default, with = "optional_duration_seconds")]
+    pub idle_timeout: Option<Duration>,
+    /// how long after `created_at` the session expires regardless of how active it is
+    #[serde(default, with = "optional_duration_seconds")]
+    pub max_lifetime: Option<Duration>,
 }
 
 impl<T> Session<T> {
+    /// how long the session should be kept by a store, derived from `max_age` if present and
+    /// otherwise from the time remaining between `created_at` and `expires`, and limited by the
+    /// session's idle timeout and remaining lifetime
+    pub fn ttl(&self) -> Option<Duration> {
+        let ttl = match self.max_age {
+            Some(max_age) => Some(max_age),
+            None => self.expires.map(|expires| expires - self.created_at),
+        };
+        [ttl, self.idle_timeout, self.remaining_lifetime()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    /// how long the session should be kept by a store after it has been used, the session's idle timeout
+    /// (or `sliding_expiration` if it has none) limited by the session's remaining lifetime
+    pub fn idle_ttl(&self, sliding_expiration: Option<Duration>) -> Option<Duration> {
+        let idle_ttl = self.idle_timeout.or(sliding_expiration)?;
+        Some(match self.remaining_lifetime() {
+            Some(remaining_lifetime) => idle_ttl.min(remaining_lifetime),
+            None => idle_ttl,
+        })
+    }
+
+    /// time remaining before the session reaches its maximum lifetime
+    pub fn remaining_lifetime(&self) -> Option<Duration> {
+        self.max_lifetime
+            .map(|max_lifetime| self.created_at + max_lifetime - Utc::now().naive_utc())
+    }
+
+    /// whether the session has outlived its maximum lifetime
+    pub fn is_expired(&self) -> bool {
+        matches!(self.remaining_lifetime(), Some(remaining_lifetime) if remaining_lifetime <= Duration::zero())
+    }
+
+    /// returns the session if it has not outlived its maximum lifetime
+    pub fn unexpired(self) -> Result<Self, SessionError> {
+        match self.is_expired() {
+            true => Err(SessionError::Expired),
+            false => Ok(self),
+        }
+    }
+
     pub fn map<U>(self, map_fn: impl FnOnce(T) -> U) -> Session<U> {
         Session {
             session_id: self.session_id,
@@ -25,6 +74,8 @@ impl<T> Session<T> {
             value: map_fn(self.value),
             max_age: self.max_age,
             expires: self.expires,
+            idle_timeout: self.idle_timeout,
+            max_lifetime: self.max_lifetime,
         }
     }
 
@@ -35,6 +86,8 @@ impl<T> Session<T> {
             value: try_map_fn(self.value)?,
             max_age: self.max_age,
             expires: self.expires,
+            idle_timeout: self.idle_timeout,
+            max_lifetime: self.max_lifetime,
         })
     }
 }
@@ -49,38 +102,60 @@ impl<T> Deref for Session<T> {
 pub trait RawSession<ParsedSession>: Sized {
     type Key;
     type Validation;
-    fn try_decode(self, key: &Self::Key, validation: &Self::Validation) -> Result<ParsedSession, Error>;
+    fn try_decode(self, key: &Self::Key, validation: &Self::Validation) -> Result<ParsedSession, SessionError>;
+    /// inserts the request's session into its extensions, or `None` when there is no session or it could not be
+    /// loaded or decoded, in which case the error is returned for the session layer's failure policy
     fn add_extensions(
-        session: Result<Option<Self>, Error>,
+        session: Result<Option<Self>, SessionError>,
         key: &Self::Key,
         validation: &Self::Validation,
         extensions: &mut Extensions,
-    );
+    ) -> Result<(), SessionError>;
 }
 
 impl<T: Clone + Send + Sync + 'static> RawSession<T> for T {
     type Key = ();
     type Validation = ();
-    fn try_decode(self, _: &Self::Key, _: &Self::Validation) -> Result<T, Error> {
+    fn try_decode(self, _: &Self::Key, _: &Self::Validation) -> Result<T, SessionError> {
         Ok(self)
     }
     fn add_extensions(
-        session: Result<Option<Self>, Error>,
+        session: Result<Option<Self>, SessionError>,
         _: &Self::Key,
         _: &Self::Validation,
         extensions: &mut Extensions,
-    ) {
+    ) -> Result<(), SessionError> {
         match session {
-            Ok(Some(session)) => extensions.insert(Some(session)),
-            _ => extensions.insert(None::<T>),
-        };
+            Ok(session) => {
+                extensions.insert(session);
+                Ok(())
+            }
+            Err(err) => {
+                extensions.insert(None::<T>);
+                Err(err)
+            }
+        }
+    }
+}
+
+mod optional_duration_seconds {
+    use ::chrono::Duration;
+    use ::serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        duration.map(|duration| duration.num_seconds()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<i64>::deserialize(deserializer)?.map(Duration::seconds))
     }
 }
 
 #[derive(Clone, Debug)]
 pub enum RequestSession<T> {
     None,
-    SessionId(Uuid),
+    /// verified session cookie whose session is loaded from the store
+    Cookie(SessionCookie),
     Session(Session<T>),
 }
 
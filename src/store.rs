@@ -1,9 +1,7 @@
 use crate::*;
-use ::anyhow::Error;
-use ::chrono::{NaiveDateTime, Utc};
+use ::chrono::{Duration, NaiveDateTime, Utc};
 use ::http::header::{HeaderValue, SET_COOKIE};
 use ::http::{HeaderMap, Request};
-use ::ring::hmac::Key;
 use ::serde::{de::DeserializeOwned, Serialize};
 use ::std::{fmt::Debug, ops::Deref, sync::Arc};
 use ::uuid::Uuid;
@@ -20,95 +18,137 @@ pub trait SessionStore: 'static + Send + Sync + Debug {
     {
         Arc::new(self) as DynSessionStore<Self::Value>
     }
-    fn key(&self) -> &Key;
+    /// keys used to sign new session cookies and to verify the cookies of incoming requests
+    fn keyring(&self) -> &Keyring;
     fn key_name(&self) -> &str;
 
-    async fn set(&self, prefix: Option<String>, session_id: &Uuid, session: &Session<Self::Value>)
-        -> Result<(), Error>;
-    async fn get(&self, session_id: &Uuid) -> Result<Session<Self::Value>, Error>;
-    async fn delete(&self, session_id: &Uuid) -> Result<(), Error>;
+    async fn set(
+        &self,
+        prefix: Option<String>,
+        session_id: &Uuid,
+        session: &Session<Self::Value>,
+    ) -> Result<(), SessionError>;
+    async fn get(&self, session_id: &Uuid) -> Result<Session<Self::Value>, SessionError>;
+    async fn delete(&self, session_id: &Uuid) -> Result<(), SessionError>;
+
+    /// reads several sessions at once, e.g. every session listed by `list_by_prefix`, returning the result of
+    /// each session in the order of `session_ids`
+    ///
+    /// missing, expired or undecodable sessions fail individually while an unavailable backend fails the whole
+    /// lookup, stores which can read many sessions in a single round trip override the default of calling `get`
+    /// for each id
+    async fn get_many(
+        &self,
+        session_ids: &[Uuid],
+    ) -> Result<Vec<Result<Session<Self::Value>, SessionError>>, SessionError> {
+        let mut sessions = Vec::with_capacity(session_ids.len());
+        for session_id in session_ids {
+            match self.get(session_id).await {
+                Err(err) if err.is_unavailable() => return Err(err),
+                session => sessions.push(session),
+            }
+        }
+        Ok(sessions)
+    }
+
+    /// reads a session kept entirely in the request's cookies, only implemented by stores
+    /// which keep sessions on the client rather than in a backend
+    fn get_from_headers(&self, _headers: &HeaderMap) -> Result<Option<Session<Self::Value>>, SessionError> {
+        Ok(None)
+    }
+
+    /// replaces the value of a stored session, keeping its id and remaining ttl
+    async fn update(&self, _session_id: &Uuid, _value: &Self::Value) -> Result<(), SessionError> {
+        Err(SessionError::Unsupported("updating sessions"))
+    }
+
+    /// applies `modify_fn` to the value of a stored session and stores the result, returning the modified session
+    ///
+    /// the default reads the session with `get` and stores it with `update`, so it is not atomic and a concurrent
+    /// modification of the same session in between is lost, stores which can read and write the session within
+    /// a transaction override it and may call `modify_fn` again when retrying a transaction which conflicted
+    async fn modify(
+        &self,
+        session_id: &Uuid,
+        modify_fn: &mut (dyn for<'v> FnMut(&'v mut Self::Value) + Send),
+    ) -> Result<Session<Self::Value>, SessionError> {
+        let mut session = self.get(session_id).await?;
+        modify_fn(&mut session.value);
+        self.update(session_id, &session.value).await?;
+        Ok(session)
+    }
+
+    /// resets the time to live of a session so that it expires `extend_by` from now
+    async fn touch(&self, _session_id: &Uuid, _extend_by: Duration) -> Result<(), SessionError> {
+        Err(SessionError::Unsupported("touching sessions"))
+    }
+
+    /// lists the ids of the sessions which were stored with the provided prefix
+    async fn list_by_prefix(&self, _prefix: &str) -> Result<Vec<Uuid>, SessionError> {
+        Err(SessionError::Unsupported("listing sessions by prefix"))
+    }
+
+    /// deletes every session which was stored with the provided prefix
+    async fn delete_by_prefix(&self, prefix: &str) -> Result<(), SessionError> {
+        for session_id in self.list_by_prefix(prefix).await? {
+            self.delete(&session_id).await?;
+        }
+        Ok(())
+    }
 
     async fn store_session_and_set_cookie(
         &self,
         response_headers: &mut HeaderMap,
         cookie_config: CookieConfig<'_, Self::Value>,
         prefix: Option<String>,
-    ) -> Result<(), Error> {
-        let cookie_value = CookieValue::new(self.key())?;
+    ) -> Result<(), SessionError> {
+        let cookie_value = CookieValue::new(cookie_config.payload.as_deref().map(<[u8]>::to_vec))?;
         let session = Session {
             session_id: cookie_value.id,
             created_at: Utc::now().naive_utc(),
             value: cookie_config.value.clone(),
             max_age: cookie_config.max_age,
             expires: cookie_config.expires,
+            idle_timeout: cookie_config.idle_timeout,
+            max_lifetime: cookie_config.max_lifetime,
         };
 
-        // for cookie formatting standards, see https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie
-        let mut cookie = format!(
-            "{}={}; SameSite={}",
-            self.key_name(),
-            cookie_value.encode(),
-            cookie_config.same_site
-        );
-        if cookie_config.http_only {
-            cookie = format!("{cookie}; HttpOnly");
-        }
-        if cookie_config.secure {
-            cookie = format!("{cookie}; Secure");
-        }
-        if let Some(domain) = cookie_config.domain {
-            cookie = format!("{cookie}; Domain={domain}");
-        }
-        if let Some(path) = cookie_config.path {
-            cookie = format!("{cookie}; Path={path}");
-        }
-        if let Some(max_age) = cookie_config.max_age {
-            cookie = format!("{cookie}; Max-Age={}", max_age.num_seconds());
-        }
-        if let Some(expires) = cookie_config.expires {
-            // for chrono formatting escape sequences, see https://docs.rs/chrono/0.4.19/chrono/format/strftime/index.html
-            // for date formatting standards in http headers, see https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Date
-            cookie = format!("{cookie}; Expires={}", expires.format("%a, %d %b %Y %H:%M:%S GMT"));
-        }
-
-        let header_value = HeaderValue::from_str(&cookie).map_err(Error::msg)?;
+        let header_value = set_cookie_header(self.key_name(), &cookie_value.encode(self.keyring())?, &cookie_config)?;
 
         self.set(prefix, &session.session_id, &session).await?;
         response_headers.append(SET_COOKIE, header_value);
         Ok(())
     }
 
-    async fn delete_session(
+    /// moves a stored session to a newly generated id, keeping its creation time, remaining ttl and prefix,
+    /// returning the session under its new id
+    async fn rotate(&self, _session_id: &Uuid, _new_session_id: &Uuid) -> Result<Session<Self::Value>, SessionError> {
+        Err(SessionError::Unsupported("rotating sessions"))
+    }
+
+    /// rotates a stored session to a newly generated id and sets the cookie for the new id, invalidating
+    /// the previous id to prevent session fixation (e.g. after signing in or escalating privileges)
+    async fn rotate_session(
         &self,
         response_headers: &mut HeaderMap,
         cookie_config: CookieConfig<'_, ()>,
-        session_id: Option<&Uuid>,
-    ) -> Result<(), Error> {
-        // for cookie formatting standards, see https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie
-        let mut cookie = format!("{}=; SameSite={}", self.key_name(), cookie_config.same_site);
-        if cookie_config.http_only {
-            cookie = format!("{cookie}; HttpOnly");
-        }
-        if cookie_config.secure {
-            cookie = format!("{cookie}; Secure");
-        }
-        if let Some(domain) = cookie_config.domain {
-            cookie = format!("{cookie}; Domain={domain}");
-        }
-        if let Some(path) = cookie_config.path {
-            cookie = format!("{cookie}; Path={path}");
-        }
+        session_id: &Uuid,
+    ) -> Result<Session<Self::Value>, SessionError> {
+        let cookie_value = CookieValue::new(cookie_config.payload.as_deref().map(<[u8]>::to_vec))?;
+        let header_value = set_cookie_header(self.key_name(), &cookie_value.encode(self.keyring())?, &cookie_config)?;
 
-        // for chrono formatting escape sequences, see https://docs.rs/chrono/0.4.19/chrono/format/strftime/index.html
-        // for date formatting standards in http headers, see https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Date
-        cookie = format!(
-            "{cookie}; Expires={}",
-            NaiveDateTime::from_timestamp_opt(0, 0)
-                .unwrap()
-                .format("%a, %d %b %Y %H:%M:%S GMT")
-        );
+        let session = self.rotate(session_id, &cookie_value.id).await?;
+        response_headers.append(SET_COOKIE, header_value);
+        Ok(session)
+    }
 
-        let header_value = HeaderValue::from_str(&cookie).map_err(Error::msg)?;
+    async fn delete_session(
+        &self,
+        response_headers: &mut HeaderMap,
+        cookie_config: CookieConfig<'_, ()>,
+        session_id: Option<&Uuid>,
+    ) -> Result<(), SessionError> {
+        let header_value = expired_cookie_header(self.key_name(), &cookie_config)?;
 
         if let Some(session_id) = session_id {
             self.delete(session_id).await?;
@@ -122,8 +162,8 @@ pub trait SessionStore: 'static + Send + Sync + Debug {
 impl<S: SessionStore + ?Sized, Wrapper: 'static + Debug + Deref<Target = S> + Send + Sync> SessionStore for Wrapper {
     type Value = S::Value;
 
-    fn key(&self) -> &Key {
-        self.deref().key()
+    fn keyring(&self) -> &Keyring {
+        self.deref().keyring()
     }
     fn key_name(&self) -> &str {
         self.deref().key_name()
@@ -133,41 +173,151 @@ impl<S: SessionStore + ?Sized, Wrapper: 'static + Debug + Deref<Target = S> + Se
         prefix: Option<String>,
         session_id: &Uuid,
         session: &Session<Self::Value>,
-    ) -> Result<(), Error> {
+    ) -> Result<(), SessionError> {
         self.deref().set(prefix, session_id, session).await
     }
-    async fn get(&self, session_id: &Uuid) -> Result<Session<Self::Value>, Error> {
+    async fn get(&self, session_id: &Uuid) -> Result<Session<Self::Value>, SessionError> {
         self.deref().get(session_id).await
     }
-    async fn delete(&self, session_id: &Uuid) -> Result<(), Error> {
+    async fn delete(&self, session_id: &Uuid) -> Result<(), SessionError> {
         self.deref().delete(session_id).await
     }
+    async fn get_many(
+        &self,
+        session_ids: &[Uuid],
+    ) -> Result<Vec<Result<Session<Self::Value>, SessionError>>, SessionError> {
+        self.deref().get_many(session_ids).await
+    }
+    fn get_from_headers(&self, headers: &HeaderMap) -> Result<Option<Session<Self::Value>>, SessionError> {
+        self.deref().get_from_headers(headers)
+    }
+    async fn update(&self, session_id: &Uuid, value: &Self::Value) -> Result<(), SessionError> {
+        self.deref().update(session_id, value).await
+    }
+    async fn modify(
+        &self,
+        session_id: &Uuid,
+        modify_fn: &mut (dyn for<'v> FnMut(&'v mut Self::Value) + Send),
+    ) -> Result<Session<Self::Value>, SessionError> {
+        self.deref().modify(session_id, modify_fn).await
+    }
+    async fn touch(&self, session_id: &Uuid, extend_by: Duration) -> Result<(), SessionError> {
+        self.deref().touch(session_id, extend_by).await
+    }
+    async fn list_by_prefix(&self, prefix: &str) -> Result<Vec<Uuid>, SessionError> {
+        self.deref().list_by_prefix(prefix).await
+    }
+    async fn delete_by_prefix(&self, prefix: &str) -> Result<(), SessionError> {
+        self.deref().delete_by_prefix(prefix).await
+    }
     async fn store_session_and_set_cookie(
         &self,
         response_headers: &mut HeaderMap,
         cookie_config: CookieConfig<'_, Self::Value>,
         prefix: Option<String>,
-    ) -> Result<(), Error> {
+    ) -> Result<(), SessionError> {
         self.deref()
             .store_session_and_set_cookie(response_headers, cookie_config, prefix)
             .await
     }
+    async fn rotate(&self, session_id: &Uuid, new_session_id: &Uuid) -> Result<Session<Self::Value>, SessionError> {
+        self.deref().rotate(session_id, new_session_id).await
+    }
+    async fn rotate_session(
+        &self,
+        response_headers: &mut HeaderMap,
+        cookie_config: CookieConfig<'_, ()>,
+        session_id: &Uuid,
+    ) -> Result<Session<Self::Value>, SessionError> {
+        self.deref()
+            .rotate_session(response_headers, cookie_config, session_id)
+            .await
+    }
     async fn delete_session(
         &self,
         response_headers: &mut HeaderMap,
         cookie_config: CookieConfig<'_, ()>,
         session_id: Option<&Uuid>,
-    ) -> Result<(), Error> {
+    ) -> Result<(), SessionError> {
         self.deref()
             .delete_session(response_headers, cookie_config, session_id)
             .await
     }
 }
 
+pub(crate) fn set_cookie_header<T: Clone>(
+    key_name: &str,
+    cookie_value: &str,
+    cookie_config: &CookieConfig<'_, T>,
+) -> Result<HeaderValue, SessionError> {
+    // for cookie formatting standards, see https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie
+    let mut cookie = format!("{}={}; SameSite={}", key_name, cookie_value, cookie_config.same_site);
+    if cookie_config.http_only {
+        cookie = format!("{cookie}; HttpOnly");
+    }
+    if cookie_config.secure {
+        cookie = format!("{cookie}; Secure");
+    }
+    if let Some(domain) = cookie_config.domain.as_ref() {
+        cookie = format!("{cookie}; Domain={domain}");
+    }
+    if let Some(path) = cookie_config.path.as_ref() {
+        cookie = format!("{cookie}; Path={path}");
+    }
+    if let Some(max_age) = cookie_config.max_age {
+        cookie = format!("{cookie}; Max-Age={}", max_age.num_seconds());
+    }
+    if let Some(expires) = cookie_config.expires {
+        // for chrono formatting escape sequences, see https://docs.rs/chrono/0.4.19/chrono/format/strftime/index.html
+        // for date formatting standards in http headers, see https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Date
+        cookie = format!("{cookie}; Expires={}", expires.format("%a, %d %b %Y %H:%M:%S GMT"));
+    }
+
+    HeaderValue::from_str(&cookie).map_err(|err| SessionError::cookie(err.to_string()))
+}
+
+/// formats a cookie which immediately expires, removing the cookie from the browser
+pub(crate) fn expired_cookie_header<T: Clone>(
+    key_name: &str,
+    cookie_config: &CookieConfig<'_, T>,
+) -> Result<HeaderValue, SessionError> {
+    // for cookie formatting standards, see https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Set-Cookie
+    let mut cookie = format!("{}=; SameSite={}", key_name, cookie_config.same_site);
+    if cookie_config.http_only {
+        cookie = format!("{cookie}; HttpOnly");
+    }
+    if cookie_config.secure {
+        cookie = format!("{cookie}; Secure");
+    }
+    if let Some(domain) = cookie_config.domain.as_ref() {
+        cookie = format!("{cookie}; Domain={domain}");
+    }
+    if let Some(path) = cookie_config.path.as_ref() {
+        cookie = format!("{cookie}; Path={path}");
+    }
+
+    // for chrono formatting escape sequences, see https://docs.rs/chrono/0.4.19/chrono/format/strftime/index.html
+    // for date formatting standards in http headers, see https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Date
+    cookie = format!(
+        "{cookie}; Expires={}",
+        NaiveDateTime::from_timestamp_opt(0, 0)
+            .unwrap()
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+    );
+
+    HeaderValue::from_str(&cookie).map_err(|err| SessionError::cookie(err.to_string()))
+}
+
 pub trait SessionValue<ReqBody: Sync, S: SessionStore> {
-    fn get_unparsed_request_session(store: &S, req: &Request<ReqBody>) -> Result<RequestSession<S::Value>, Error> {
-        match get_session_id_from_request(store, req) {
-            Some(session_id) => Ok(RequestSession::SessionId(session_id)),
+    fn get_unparsed_request_session(
+        store: &S,
+        req: &Request<ReqBody>,
+    ) -> Result<RequestSession<S::Value>, SessionError> {
+        if let Some(session) = store.get_from_headers(req.headers())? {
+            return Ok(RequestSession::Session(session));
+        }
+        match verify_session_cookie(store, req) {
+            Some(session_cookie) => Ok(RequestSession::Cookie(session_cookie)),
             None => Ok(RequestSession::None),
         }
     }
@@ -1,7 +1,7 @@
 use crate::{
-    get_session_id_from_request, DynSessionStore, RawSession, RequestSession, Session, SessionStore, SessionValue,
+    verify_session_cookie, DynSessionStore, RawSession, RequestSession, Session, SessionError, SessionStore,
+    SessionValue,
 };
-use anyhow::Error;
 use chrono::{Duration, NaiveDateTime, Utc};
 use derivative::Derivative;
 use derive_more::*;
@@ -43,9 +43,10 @@ where
         self,
         key: &Self::Key,
         validation: &Self::Validation,
-    ) -> Result<AccountSession<AccountId, Fields>, anyhow::Error> {
+    ) -> Result<AccountSession<AccountId, Fields>, SessionError> {
         self.try_map(|value| {
-            let token_data = decode::<AccountSessionClaims<AccountId, Fields>>(&value.token, key, validation)?;
+            let token_data = decode::<AccountSessionClaims<AccountId, Fields>>(&value.token, key, validation)
+                .map_err(SessionError::token)?;
             Ok(AccountSessionToken {
                 token: value.token,
                 claims: token_data.claims,
@@ -53,27 +54,30 @@ where
         })
     }
     fn add_extensions(
-        session: Result<Option<Self>, anyhow::Error>,
+        session: Result<Option<Self>, SessionError>,
         key: &Self::Key,
         validation: &Self::Validation,
         extensions: &mut http::Extensions,
-    ) {
-        let parsed_session: Option<AccountSession<AccountId, Fields>> = match session {
+    ) -> Result<(), SessionError> {
+        let parsed_session: Result<Option<AccountSession<AccountId, Fields>>, SessionError> = match session {
             Ok(Some(session)) => {
                 extensions.insert(Some(session.clone()));
-                session.try_decode(key, validation).ok()
+                session.try_decode(key, validation).map(Some)
             }
-            _ => None,
+            Ok(None) => Ok(None),
+            Err(err) => Err(err),
         };
         match parsed_session {
-            Some(parsed_session) => {
+            Ok(Some(parsed_session)) => {
                 extensions.insert(Some(AccountSessionSubject(parsed_session.account_id.clone())));
                 extensions.insert(Some(parsed_session));
+                Ok(())
             }
-            _ => {
+            parsed_session => {
                 extensions.insert(None::<AccountSessionSubject<AccountId>>);
                 extensions.insert(None::<AccountSession<AccountId, Fields>>);
                 extensions.insert(None::<Session<AccountSessionToken<()>>>);
+                parsed_session.map(|_| ())
             }
         }
     }
@@ -84,22 +88,30 @@ impl<ReqBody: Sync, S> SessionValue<ReqBody, S> for AccountSessionToken<()>
 where
     S: SessionStore<Value = Self>,
 {
-    fn get_unparsed_request_session(store: &S, req: &Request<ReqBody>) -> Result<RequestSession<S::Value>, Error> {
+    fn get_unparsed_request_session(
+        store: &S,
+        req: &Request<ReqBody>,
+    ) -> Result<RequestSession<S::Value>, SessionError> {
         if let Some(service_account_jwt) = req.headers().get(HTTP_ACCOUNT_SESSION_JWT_HEADER) {
             return Ok(RequestSession::Session(Session {
                 session_id: Uuid::new_v4(),
                 created_at: Utc::now().naive_utc(),
                 value: AccountSessionToken {
-                    token: service_account_jwt.to_str()?.into(),
+                    token: service_account_jwt.to_str().map_err(SessionError::token)?.into(),
                     claims: (),
                 },
                 max_age: None,
                 expires: None,
+                idle_timeout: None,
+                max_lifetime: None,
             }));
         }
 
-        match get_session_id_from_request(store, req) {
-            Some(session_id) => Ok(RequestSession::SessionId(session_id)),
+        if let Some(session) = store.get_from_headers(req.headers())? {
+            return Ok(RequestSession::Session(session));
+        }
+        match verify_session_cookie(store, req) {
+            Some(session_cookie) => Ok(RequestSession::Cookie(session_cookie)),
             None => Ok(RequestSession::None),
         }
     }
@@ -235,9 +247,9 @@ impl<AccountId, Fields> AccountSessionClaims<AccountId, Fields> {
 }
 
 impl<AccountId: Serialize, Fields: Serialize> AccountSessionClaims<AccountId, Fields> {
-    pub fn encode(self, header: &Header, encoding_key: &EncodingKey) -> Result<AccountSessionToken<()>, anyhow::Error> {
+    pub fn encode(self, header: &Header, encoding_key: &EncodingKey) -> Result<AccountSessionToken<()>, SessionError> {
         Ok(AccountSessionToken {
-            token: encode(header, &self, encoding_key)?,
+            token: encode(header, &self, encoding_key).map_err(SessionError::token)?,
             claims: (),
         })
     }